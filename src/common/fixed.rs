@@ -99,4 +99,8 @@ impl Fixed {
     pub fn new<N: Into<Self>>(value: N) -> Fixed {
         value.into()
     }
+
+    pub fn abs(self) -> Fixed {
+        Fixed(self.0.abs())
+    }
 }
@@ -31,10 +31,38 @@ pub struct Quadrangle {
     pub points: [Point; 4],
 }
 
+impl Quadrangle {
+    pub fn width(&self) -> Fixed {
+        let x1 = self.points.iter().map(|p| p.x).min().unwrap();
+        let x2 = self.points.iter().map(|p| p.x).max().unwrap();
+        x2 - x1
+    }
+
+    // Thin upright quadrangles are filled lines, e.g. brackets and thick barlines
+    pub fn as_vert_line(&self) -> Option<VertLine> {
+        let x1 = self.points.iter().map(|p| p.x).min().unwrap();
+        let x2 = self.points.iter().map(|p| p.x).max().unwrap();
+        let y1 = self.points.iter().map(|p| p.y).min().unwrap();
+        let y2 = self.points.iter().map(|p| p.y).max().unwrap();
+        if y2 - y1 > (x2 - x1) * 4 {
+            Some(VertLine::new((x1 + x2) / 2, y1, y2))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Curve {
+    pub points: [Point; 4],
+}
+
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub struct Polygon {
     pub lines: Vec<Line>,
+    pub curves: Vec<Curve>,
     pub point: Point,
+    pub width: Fixed,
 }
 
 pub enum PolygonRes {
@@ -42,14 +70,18 @@ pub enum PolygonRes {
     VertLine(VertLine),
     Line(Line),
     Quadrangle(Quadrangle),
+    Dot(Point),
+    Curve(Curve),
     Empty,
 }
 
 impl Polygon {
-    pub fn new<X: Into<Fixed>, Y: Into<Fixed>>(x: X, y: Y) -> Self {
+    pub fn new<X: Into<Fixed>, Y: Into<Fixed>, W: Into<Fixed>>(x: X, y: Y, width: W) -> Self {
         Self {
             point: Point::new(x, y),
             lines: vec![],
+            curves: vec![],
+            width: width.into(),
         }
     }
 
@@ -58,12 +90,40 @@ impl Polygon {
         self.lines.push(Line::new(point.x, point.y, x, y));
     }
 
+    pub fn curve(&mut self, c1: Point, c2: Point, to: Point) {
+        let from = std::mem::replace(&mut self.point, to.clone());
+        self.curves.push(Curve {
+            points: [from, c1, c2, to],
+        });
+    }
+
+    // Closed paths of curves only, as wide as they are tall, are filled dots;
+    // wide and flat ones are ties and slurs, kept by their first edge
+    fn build_curves(&self) -> PolygonRes {
+        let points = self.curves.iter().flat_map(|curve| curve.points.iter());
+        let x1 = points.clone().map(|p| p.x).min().unwrap();
+        let x2 = points.clone().map(|p| p.x).max().unwrap();
+        let y1 = points.clone().map(|p| p.y).min().unwrap();
+        let y2 = points.map(|p| p.y).max().unwrap();
+        let (width, height) = (x2 - x1, y2 - y1);
+        if self.lines.is_empty() && self.curves.len() >= 3 && (width - height).abs() * 4 <= width {
+            PolygonRes::Dot(Point::new((x1 + x2) / 2, (y1 + y2) / 2))
+        } else if width > height * 2 {
+            PolygonRes::Curve(self.curves[0].clone())
+        } else {
+            PolygonRes::Empty
+        }
+    }
+
     pub fn build(&self) -> PolygonRes {
+        if !self.curves.is_empty() {
+            return self.build_curves();
+        }
         match self.lines.len() {
             1 => {
                 let line = &self.lines[0];
                 if line.y1 == line.y2 {
-                    PolygonRes::HorzLine(HorzLine::new(line.x1, line.x2, line.y1))
+                    PolygonRes::HorzLine(HorzLine::new(line.x1, line.x2, line.y1, self.width))
                 } else if line.x1 == line.x2 {
                     PolygonRes::VertLine(VertLine::new(line.x1, line.y1, line.y2))
                 } else {
@@ -86,27 +146,48 @@ impl Polygon {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct HorzLine {
     pub x1: Fixed,
     pub x2: Fixed,
     pub y: Fixed,
+    pub thickness: Fixed,
 }
 
 impl HorzLine {
-    pub fn new<X1: Into<Fixed>, X2: Into<Fixed>, Y: Into<Fixed>>(x1: X1, x2: X2, y: Y) -> Self {
+    pub fn new<X1: Into<Fixed>, X2: Into<Fixed>, Y: Into<Fixed>, T: Into<Fixed>>(
+        x1: X1,
+        x2: X2,
+        y: Y,
+        thickness: T,
+    ) -> Self {
         let x1 = x1.into();
         let x2 = x2.into();
         Self {
             x1: std::cmp::min(x1, x2),
             x2: std::cmp::max(x1, x2),
             y: y.into(),
+            thickness: thickness.into(),
         }
     }
 
     pub fn len(&self) -> Fixed {
         self.x2 - self.x1
     }
+
+    // Joins `other` into `self` if both lie on the same row and touch or overlap
+    pub fn merge(&mut self, other: &HorzLine) -> bool {
+        if self.y != other.y || self.thickness != other.thickness {
+            return false;
+        }
+        let gap = self.thickness / 2;
+        if other.x1 > self.x2 + gap || self.x1 > other.x2 + gap {
+            return false;
+        }
+        self.x1 = std::cmp::min(self.x1, other.x1);
+        self.x2 = std::cmp::max(self.x2, other.x2);
+        true
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
@@ -143,21 +224,239 @@ impl Point {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sign {
+    G,
+    F,
+    C,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accidental {
+    Sharp,
+    Flat,
+    Natural,
+}
+
+impl Accidental {
+    pub fn alter(self) -> i8 {
+        match self {
+            Accidental::Sharp => 1,
+            Accidental::Flat => -1,
+            Accidental::Natural => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dynamic {
+    Ppp,
+    Pp,
+    P,
+    Mp,
+    Mf,
+    F,
+    Ff,
+    Fff,
+    Sfz,
+    Fp,
+}
+
+impl Dynamic {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.trim() {
+            "ppp" => Some(Dynamic::Ppp),
+            "pp" => Some(Dynamic::Pp),
+            "p" => Some(Dynamic::P),
+            "mp" => Some(Dynamic::Mp),
+            "mf" => Some(Dynamic::Mf),
+            "f" => Some(Dynamic::F),
+            "ff" => Some(Dynamic::Ff),
+            "fff" => Some(Dynamic::Fff),
+            "sfz" => Some(Dynamic::Sfz),
+            "fp" => Some(Dynamic::Fp),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Dynamic::Ppp => "ppp",
+            Dynamic::Pp => "pp",
+            Dynamic::P => "p",
+            Dynamic::Mp => "mp",
+            Dynamic::Mf => "mf",
+            Dynamic::F => "f",
+            Dynamic::Ff => "ff",
+            Dynamic::Fff => "fff",
+            Dynamic::Sfz => "sfz",
+            Dynamic::Fp => "fp",
+        }
+    }
+
+    // Level the music stays at afterwards; sforzando only stresses its note
+    // and forte-piano drops right away
+    pub fn velocity(self) -> Option<u8> {
+        match self {
+            Dynamic::Ppp => Some(16),
+            Dynamic::Pp => Some(33),
+            Dynamic::P | Dynamic::Fp => Some(49),
+            Dynamic::Mp => Some(64),
+            Dynamic::Mf => Some(80),
+            Dynamic::F => Some(96),
+            Dynamic::Ff => Some(112),
+            Dynamic::Fff => Some(127),
+            Dynamic::Sfz => None,
+        }
+    }
+
+    // Level of the note under the marking itself
+    pub fn attack(self) -> u8 {
+        match self {
+            Dynamic::Sfz => 127,
+            Dynamic::Fp => 96,
+            etc => etc.velocity().unwrap(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Articulation {
+    Staccato,
+    Staccatissimo,
+    Tenuto,
+    Accent,
+    Marcato,
+    Fermata,
+}
+
+impl Articulation {
+    // Share of the written length actually sounding
+    pub fn shorten(self, len: i32) -> i32 {
+        match self {
+            Articulation::Staccato => len / 2,
+            Articulation::Staccatissimo => len / 4,
+            _ => len,
+        }
+    }
+
+    pub fn stress(self) -> u8 {
+        match self {
+            Articulation::Accent => 16,
+            Articulation::Marcato => 24,
+            _ => 0,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Type {
     Head(u8),
-    Wing(u8),
+    Wing(u8, Direction),
     Rest(u8),
+    // H-bar standing for several bars of rest, counted by the number above it
+    Multirest,
+    Clef(Sign, i8),
+    Accidental(Accidental),
+    Parenthesis,
+    Digit(u8),
+    Time(u8, u8),
+    Dot,
+    Segno,
+    Coda,
+    Dynamic(Dynamic),
+    Articulation(Articulation),
+}
+
+// Text set in other fonts than the music one
+#[derive(Debug, Clone)]
+pub struct Text {
+    pub text: String,
+    pub point: Point,
+}
+
+impl Text {
+    pub fn new(text: String, point: Point) -> Self {
+        Self { text, point }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Object {
     pub t: Type,
     pub point: Point,
+    // Drawn at a reduced size, as grace and cue notes are
+    pub small: bool,
 }
 
 impl Object {
     pub fn new(t: Type, point: Point) -> Self {
-        Self { t, point }
+        Self {
+            t,
+            point,
+            small: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_touching_segments() {
+        let mut line = HorzLine::new(0, 100, 50, 1);
+        assert!(line.merge(&HorzLine::new(100, 220, 50, 1)));
+        assert!(line.merge(&HorzLine::new(80, 150, 50, 1)));
+        assert_eq!(line, HorzLine::new(0, 220, 50, 1));
+    }
+
+    #[test]
+    fn merge_bridges_half_a_stroke() {
+        let mut line = HorzLine::new(0, 100, 50, 2);
+        assert!(line.merge(&HorzLine::new(101, 200, 50, 2)));
+        assert!(!line.merge(&HorzLine::new(202, 300, 50, 2)));
+        assert_eq!(line, HorzLine::new(0, 200, 50, 2));
+    }
+
+    #[test]
+    fn merge_keeps_other_rows_apart() {
+        let mut line = HorzLine::new(0, 100, 50, 1);
+        assert!(!line.merge(&HorzLine::new(50, 150, 51, 1)));
+        assert!(!line.merge(&HorzLine::new(50, 150, 50, 2)));
+        assert_eq!(line, HorzLine::new(0, 100, 50, 1));
+    }
+
+    #[test]
+    fn dynamics_by_name() {
+        for name in ["ppp", "pp", "p", "mp", "mf", "f", "ff", "fff", "sfz", "fp"].iter() {
+            assert_eq!(Dynamic::parse(name).map(Dynamic::name), Some(*name));
+        }
+        assert_eq!(Dynamic::parse(" mf "), Some(Dynamic::Mf));
+        assert_eq!(Dynamic::parse("cresc."), None);
+        assert_eq!(Dynamic::parse("F"), None);
+    }
+
+    #[test]
+    fn accents_leave_the_level_alone() {
+        assert_eq!(Dynamic::Sfz.velocity(), None);
+        assert_eq!(Dynamic::Sfz.attack(), 127);
+        assert_eq!(Dynamic::Fp.velocity(), Dynamic::P.velocity());
+        assert_eq!(Dynamic::Fp.attack(), 96);
+        assert!(Dynamic::Pp.attack() < Dynamic::P.attack());
+    }
+
+    #[test]
+    fn only_staccatos_shorten() {
+        assert_eq!(Articulation::Staccato.shorten(480), 240);
+        assert_eq!(Articulation::Staccatissimo.shorten(480), 120);
+        assert_eq!(Articulation::Tenuto.shorten(480), 480);
+        assert_eq!(Articulation::Fermata.shorten(480), 480);
     }
 }
@@ -1,15 +1,15 @@
-#[macro_use]
-extern crate failure;
-
 mod common;
+mod musicxml;
 mod parser;
 mod recognizer;
 mod smf;
 mod svg;
 
 use lopdf::{Document, Object};
+use musicxml::MusicXml;
 use parser::Parser;
 use recognizer::Recognizer;
+use smf::Smf;
 
 fn main() -> Result<(), failure::Error> {
     let mut doc = Document::load("target/input.pdf")?;
@@ -22,7 +22,17 @@ fn main() -> Result<(), failure::Error> {
             if let Some(Object::Stream(ref mut stream)) = doc.get_object_mut(object_id) {
                 let parsed = Parser::new(stream)?;
                 let mut recognized = Recognizer::new(parsed);
-                let _res = recognized.process();
+                let collectors = recognized.process()?;
+                for warning in recognized.warnings.iter() {
+                    println!("{}", warning);
+                }
+                let mut smf = Smf::new(152);
+                // Chord symbols are only played along on request
+                if std::env::args().any(|arg| arg == "--accompany") {
+                    smf = smf.accompany();
+                }
+                smf.write(&collectors);
+                MusicXml::new().write(&collectors, "target/output.musicxml")?;
             }
         }
         break; // TODO: Multiple pages
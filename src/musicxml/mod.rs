@@ -0,0 +1,558 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::common::{Articulation, Direction, Sign};
+use crate::recognizer::{
+    Barline, Change, Chord, Clef, Collector, Grace, Harmony, Lyric, Measure, Note, Pitch, Rest,
+    Seventh, Slur, Tempo, Tuplet, Wedge,
+};
+
+// Durations are written in ticks, as in MIDI
+const DIVISIONS: i32 = 480;
+
+pub struct MusicXml {
+    lines: Vec<String>,
+    // Voices and verses in the middle of a word
+    words: HashSet<(u8, u8)>,
+}
+
+impl MusicXml {
+    pub fn new() -> Self {
+        Self {
+            lines: vec![
+                r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>"#.to_string(),
+                r#"<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 3.1 Partwise//EN" "http://www.musicxml.org/dtds/partwise.dtd">"#.to_string(),
+            ],
+            words: HashSet::new(),
+        }
+    }
+
+    fn push<S: Into<String>>(&mut self, depth: usize, line: S) {
+        self.lines
+            .push(format!("{}{}", "  ".repeat(depth), line.into()));
+    }
+
+    fn kind(len: i32) -> Option<&'static str> {
+        match len {
+            3840 => Some("breve"),
+            1920 => Some("whole"),
+            960 => Some("half"),
+            480 => Some("quarter"),
+            240 => Some("eighth"),
+            120 => Some("16th"),
+            60 => Some("32nd"),
+            30 => Some("64th"),
+            _ => None,
+        }
+    }
+
+    // Note type from the written length, before tuplets and dots, then the dots and the
+    // tuplet ratio
+    fn written(&mut self, len: i32, dots: u8, tuplet: Option<Tuplet>) {
+        let len = tuplet.map_or(len, |tuplet| {
+            len * i32::from(tuplet.actual) / i32::from(tuplet.normal)
+        });
+        let len = len * (1 << dots) / ((2 << dots) - 1);
+        if let Some(kind) = Self::kind(len) {
+            self.push(4, format!("<type>{}</type>", kind));
+        }
+        for _ in 0..dots {
+            self.push(4, "<dot/>");
+        }
+        if let Some(tuplet) = tuplet {
+            self.push(4, "<time-modification>");
+            self.push(5, format!("<actual-notes>{}</actual-notes>", tuplet.actual));
+            self.push(5, format!("<normal-notes>{}</normal-notes>", tuplet.normal));
+            self.push(4, "</time-modification>");
+        }
+    }
+
+    fn attributes(&mut self, first: bool, measure: &Measure) {
+        if !first && measure.clef.is_none() && measure.key.is_none() && measure.time.is_none() {
+            return;
+        }
+        self.push(3, "<attributes>");
+        if first {
+            self.push(4, format!("<divisions>{}</divisions>", DIVISIONS));
+        }
+        if let Some(key) = measure.key {
+            self.push(4, format!("<key><fifths>{}</fifths></key>", key.fifths));
+        }
+        if let Some(time) = measure.time {
+            self.push(
+                4,
+                format!(
+                    "<time><beats>{}</beats><beat-type>{}</beat-type></time>",
+                    time.beats, time.beat_type
+                ),
+            );
+        }
+        if let Some(clef) = measure.clef {
+            self.clef(&clef);
+        }
+        self.push(3, "</attributes>");
+    }
+
+    fn barline(&mut self, location: &str, barline: Barline) {
+        let (style, repeat) = match barline {
+            Barline::Single => return,
+            Barline::Double => ("light-light", None),
+            Barline::Final => ("light-heavy", None),
+            Barline::StartRepeat => ("heavy-light", Some("forward")),
+            Barline::EndRepeat | Barline::Repeat => ("light-heavy", Some("backward")),
+            Barline::Dashed => ("dashed", None),
+        };
+        self.push(3, format!(r#"<barline location="{}">"#, location));
+        self.push(4, format!("<bar-style>{}</bar-style>", style));
+        if let Some(repeat) = repeat {
+            self.push(4, format!(r#"<repeat direction="{}"/>"#, repeat));
+        }
+        self.push(3, "</barline>");
+    }
+
+    fn clef(&mut self, clef: &Clef) {
+        let sign = match clef.sign {
+            Sign::G => "G",
+            Sign::F => "F",
+            Sign::C => "C",
+        };
+        self.push(4, "<clef>");
+        self.push(5, format!("<sign>{}</sign>", sign));
+        self.push(5, format!("<line>{}</line>", clef.line));
+        if clef.octave != 0 {
+            self.push(
+                5,
+                format!("<clef-octave-change>{}</clef-octave-change>", clef.octave),
+            );
+        }
+        self.push(4, "</clef>");
+    }
+
+    fn pitch(&mut self, pitch: &Pitch) {
+        self.push(4, "<pitch>");
+        self.push(
+            5,
+            format!(
+                "<step>{}</step>",
+                &"CDEFGAB"[pitch.degree()..=pitch.degree()]
+            ),
+        );
+        if pitch.alter != 0 {
+            self.push(5, format!("<alter>{}</alter>", pitch.alter));
+        }
+        self.push(
+            5,
+            format!("<octave>{}</octave>", pitch.step.div_euclid(7) + 4),
+        );
+        self.push(4, "</pitch>");
+    }
+
+    fn harmony(&mut self, harmony: &Harmony) {
+        let step = |pitch: &Pitch| &"CDEFGAB"[pitch.degree()..=pitch.degree()];
+        self.push(3, "<harmony>");
+        self.push(4, "<root>");
+        self.push(5, format!("<root-step>{}</root-step>", step(&harmony.root)));
+        if harmony.root.alter != 0 {
+            self.push(
+                5,
+                format!("<root-alter>{}</root-alter>", harmony.root.alter),
+            );
+        }
+        self.push(4, "</root>");
+        let (kind, seventh) = harmony.kind();
+        self.push(
+            4,
+            format!(
+                r#"<kind text="{}">{}</kind>"#,
+                Self::escape(&harmony.text),
+                kind
+            ),
+        );
+        if let Some(bass) = &harmony.bass {
+            self.push(4, "<bass>");
+            self.push(5, format!("<bass-step>{}</bass-step>", step(bass)));
+            if bass.alter != 0 {
+                self.push(5, format!("<bass-alter>{}</bass-alter>", bass.alter));
+            }
+            self.push(4, "</bass>");
+        }
+        // Whatever the kind does not say, the seventh measured from the dominant one
+        let seventh = match harmony.seventh {
+            Some(Seventh::Minor) if !seventh => Some((7, 0)),
+            Some(Seventh::Major) if !seventh => Some((7, 1)),
+            Some(Seventh::Diminished) if !seventh => Some((7, -1)),
+            _ => None,
+        };
+        for &(degree, alter) in seventh.iter().chain(harmony.extensions.iter()) {
+            let kind = if degree == 5 { "alter" } else { "add" };
+            self.push(4, "<degree>");
+            self.push(5, format!("<degree-value>{}</degree-value>", degree));
+            self.push(5, format!("<degree-alter>{}</degree-alter>", alter));
+            self.push(5, format!("<degree-type>{}</degree-type>", kind));
+            self.push(4, "</degree>");
+        }
+        self.push(3, "</harmony>");
+    }
+
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+
+    // Bare metronome marks are shown as such, in the note they count, anything else as
+    // the words printed
+    fn tempo(&mut self, tempo: &Tempo) {
+        self.push(3, r#"<direction placement="above">"#);
+        self.push(4, "<direction-type>");
+        match tempo.change {
+            Change::Set(bpm) if tempo.words.is_empty() => {
+                let (unit, number) = tempo.beat.unwrap_or((480, bpm));
+                let (kind, dotted, number) = match (Self::kind(unit), Self::kind(unit * 2 / 3)) {
+                    (Some(kind), _) => (kind, false, number),
+                    (None, Some(kind)) => (kind, true, number),
+                    (None, None) => ("quarter", false, bpm),
+                };
+                self.push(5, "<metronome>");
+                self.push(6, format!("<beat-unit>{}</beat-unit>", kind));
+                if dotted {
+                    self.push(6, "<beat-unit-dot/>");
+                }
+                self.push(6, format!("<per-minute>{}</per-minute>", number));
+                self.push(5, "</metronome>");
+            }
+            _ => self.push(5, format!("<words>{}</words>", Self::escape(&tempo.words))),
+        }
+        self.push(4, "</direction-type>");
+        if let Change::Set(bpm) = tempo.change {
+            self.push(4, format!(r#"<sound tempo="{}"/>"#, bpm));
+        }
+        self.push(3, "</direction>");
+    }
+
+    fn direction(&mut self, kind: String) {
+        self.push(3, r#"<direction placement="below">"#);
+        self.push(4, "<direction-type>");
+        self.push(5, kind);
+        self.push(4, "</direction-type>");
+        self.push(3, "</direction>");
+    }
+
+    // Dynamics and hairpin openings come before the chord, hairpin ends after it
+    fn wedges(&mut self, chord: &Chord, stop: bool) {
+        for wedge in chord.wedges.iter() {
+            let kind = match wedge {
+                Wedge::Crescendo if !stop => "crescendo",
+                Wedge::Diminuendo if !stop => "diminuendo",
+                Wedge::Stop if stop => "stop",
+                _ => continue,
+            };
+            self.direction(format!(r#"<wedge type="{}"/>"#, kind));
+        }
+    }
+
+    fn articulations(&mut self, articulations: &[Articulation]) {
+        let marks = articulations
+            .iter()
+            .filter_map(|articulation| match articulation {
+                Articulation::Staccato => Some("<staccato/>"),
+                Articulation::Staccatissimo => Some("<staccatissimo/>"),
+                Articulation::Tenuto => Some("<tenuto/>"),
+                Articulation::Accent => Some("<accent/>"),
+                Articulation::Marcato => Some("<strong-accent/>"),
+                Articulation::Fermata => None,
+            })
+            .collect::<Vec<_>>();
+        if !marks.is_empty() {
+            self.push(5, "<articulations>");
+            for mark in marks {
+                self.push(6, mark);
+            }
+            self.push(5, "</articulations>");
+        }
+        if articulations.contains(&Articulation::Fermata) {
+            self.push(5, "<fermata/>");
+        }
+    }
+
+    fn lyrics(&mut self, lyrics: &[Lyric], voice: u8) {
+        for lyric in lyrics {
+            let open = self.words.contains(&(voice, lyric.verse));
+            let syllabic = match (open, lyric.hyphen) {
+                (false, false) => "single",
+                (false, true) => "begin",
+                (true, true) => "middle",
+                (true, false) => "end",
+            };
+            if lyric.hyphen {
+                self.words.insert((voice, lyric.verse));
+            } else {
+                self.words.remove(&(voice, lyric.verse));
+            }
+            self.push(4, format!(r#"<lyric number="{}">"#, lyric.verse));
+            self.push(5, format!("<syllabic>{}</syllabic>", syllabic));
+            self.push(5, format!("<text>{}</text>", Self::escape(&lyric.text)));
+            if lyric.extend {
+                self.push(5, "<extend/>");
+            }
+            self.push(4, "</lyric>");
+        }
+    }
+
+    fn chord(&mut self, chord: &Chord, voice: u8, held: &HashSet<Pitch>) {
+        if let Some(tempo) = &chord.tempo {
+            self.tempo(tempo);
+        }
+        if let Some(dynamic) = chord.dynamic {
+            self.direction(format!("<dynamics><{}/></dynamics>", dynamic.name()));
+        }
+        self.wedges(chord, false);
+        for (index, tone) in chord.tones.iter().enumerate() {
+            let ties = [
+                ("stop", held.contains(tone)),
+                ("start", chord.tied.contains(tone)),
+            ];
+            self.push(3, "<note>");
+            match chord.grace {
+                Some(Grace::Acciaccatura) => self.push(4, r#"<grace slash="yes"/>"#),
+                Some(Grace::Appoggiatura) => self.push(4, "<grace/>"),
+                None if chord.cue => self.push(4, "<cue/>"),
+                None => {}
+            }
+            if index != 0 {
+                self.push(4, "<chord/>");
+            }
+            self.pitch(tone);
+            // Grace notes take no time
+            if chord.grace.is_none() {
+                self.push(4, format!("<duration>{}</duration>", chord.len));
+            }
+            for (tie, _) in ties.iter().filter(|(_, tied)| *tied) {
+                self.push(4, format!(r#"<tie type="{}"/>"#, tie));
+            }
+            self.push(4, format!("<voice>{}</voice>", voice));
+            self.written(chord.len, chord.dots, chord.tuplet);
+            match chord.stem {
+                Some(Direction::Up) => self.push(4, "<stem>up</stem>"),
+                Some(Direction::Down) => self.push(4, "<stem>down</stem>"),
+                None => {}
+            }
+            // Slurs and articulations are marked once per chord
+            let (slurs, articulations) = if index == 0 {
+                (chord.slurs.as_slice(), chord.articulations.as_slice())
+            } else {
+                (&[][..], &[][..])
+            };
+            if !slurs.is_empty() || !articulations.is_empty() || ties.iter().any(|(_, tied)| *tied)
+            {
+                self.push(4, "<notations>");
+                for (tie, _) in ties.iter().filter(|(_, tied)| *tied) {
+                    self.push(5, format!(r#"<tied type="{}"/>"#, tie));
+                }
+                for slur in slurs {
+                    let slur = match slur {
+                        Slur::Start => "start",
+                        Slur::Stop => "stop",
+                    };
+                    self.push(5, format!(r#"<slur type="{}" number="1"/>"#, slur));
+                }
+                self.articulations(articulations);
+                self.push(4, "</notations>");
+            }
+            if index == 0 {
+                self.lyrics(&chord.lyrics, voice);
+            }
+            self.push(3, "</note>");
+        }
+        self.wedges(chord, true);
+    }
+
+    // Rests filling the measure are written without a type, whatever its length
+    fn rest(&mut self, rest: &Rest, voice: u8) {
+        self.push(3, "<note>");
+        if rest.measure {
+            self.push(4, r#"<rest measure="yes"/>"#);
+        } else {
+            self.push(4, "<rest/>");
+        }
+        self.push(4, format!("<duration>{}</duration>", rest.len));
+        self.push(4, format!("<voice>{}</voice>", voice));
+        if !rest.measure {
+            self.written(rest.len, rest.dots, rest.tuplet);
+        }
+        if rest.fermata {
+            self.push(4, "<notations>");
+            self.push(5, "<fermata/>");
+            self.push(4, "</notations>");
+        }
+        self.push(3, "</note>");
+    }
+
+    pub fn write<P: AsRef<Path>>(&mut self, collectors: &[Collector], path: P) -> io::Result<()> {
+        self.score(collectors);
+        fs::write(path, self.lines.join("\n") + "\n")
+    }
+
+    // One part per staff that has any voice
+    fn score(&mut self, collectors: &[Collector]) {
+        let mut staves = collectors
+            .iter()
+            .map(|collector| collector.staff)
+            .collect::<Vec<_>>();
+        staves.sort_unstable();
+        staves.dedup();
+        self.push(0, r#"<score-partwise version="3.1">"#);
+        self.push(1, "<part-list>");
+        for &staff in staves.iter() {
+            self.push(2, format!(r#"<score-part id="P{}">"#, staff + 1));
+            self.push(3, format!("<part-name>Staff {}</part-name>", staff + 1));
+            self.push(2, "</score-part>");
+        }
+        self.push(1, "</part-list>");
+        for &staff in staves.iter() {
+            let mut voices = collectors
+                .iter()
+                .filter(|collector| collector.staff == staff)
+                .collect::<Vec<_>>();
+            voices.sort_by_key(|collector| collector.voice);
+            self.push(1, format!(r#"<part id="P{}">"#, staff + 1));
+            // Tones tied over from the previous chord, by voice
+            let mut held = vec![HashSet::new(); voices.len()];
+            self.words.clear();
+            for (number, measure) in voices[0].measures.iter().enumerate() {
+                self.push(2, format!(r#"<measure number="{}">"#, number + 1));
+                self.barline("left", measure.left);
+                self.attributes(number == 0, measure);
+                for (index, collector) in voices.iter().enumerate() {
+                    if index != 0 {
+                        // Each voice starts over from the beginning of the measure
+                        let len = voices[index - 1].span(number);
+                        self.push(3, format!("<backup><duration>{}</duration></backup>", len));
+                    }
+                    // Chord symbols go with the first voice, ahead of the note they start on
+                    let harmonies = if index == 0 {
+                        measure.harmonies.as_slice()
+                    } else {
+                        &[]
+                    };
+                    let mut harmonies = harmonies.iter().peekable();
+                    let mut position = 0;
+                    for note in collector.measure(number).iter() {
+                        while let Some((_, harmony)) =
+                            harmonies.next_if(|(onset, _)| *onset <= position)
+                        {
+                            self.harmony(harmony);
+                        }
+                        position += note.len();
+                        match note {
+                            Note::Chord(chord) => {
+                                self.chord(chord, collector.voice, &held[index]);
+                                held[index] = chord.tied.iter().cloned().collect();
+                            }
+                            Note::Rest(rest) => {
+                                self.rest(rest, collector.voice);
+                                held[index].clear();
+                            }
+                            Note::Space(len) => {
+                                self.push(
+                                    3,
+                                    format!(
+                                        "<forward><duration>{}</duration><voice>{}</voice></forward>",
+                                        len, collector.voice
+                                    ),
+                                );
+                                held[index].clear();
+                            }
+                        }
+                    }
+                    for (_, harmony) in harmonies {
+                        self.harmony(harmony);
+                    }
+                }
+                self.barline("right", measure.right);
+                self.push(2, "</measure>");
+            }
+            self.push(1, "</part>");
+        }
+        self.push(0, "</score-partwise>");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rest(rest: Rest) -> Vec<String> {
+        let mut xml = MusicXml::new();
+        xml.lines.clear();
+        xml.rest(&rest, 1);
+        xml.lines
+            .iter()
+            .map(|line| line.trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn dotted_rest() {
+        let mut dotted = Rest::new(360);
+        dotted.dots = 1;
+        let lines = rest(dotted);
+        assert!(lines.contains(&"<type>eighth</type>".to_string()));
+        assert!(lines.contains(&"<dot/>".to_string()));
+    }
+
+    #[test]
+    fn tuplet_rest() {
+        let mut triplet = Rest::new(160);
+        triplet.tuplet = Some(Tuplet::new(3));
+        let lines = rest(triplet);
+        assert!(lines.contains(&"<type>eighth</type>".to_string()));
+        assert!(lines.contains(&"<actual-notes>3</actual-notes>".to_string()));
+        assert!(lines.contains(&"<normal-notes>2</normal-notes>".to_string()));
+    }
+
+    #[test]
+    fn rest_under_a_fermata() {
+        let mut held = Rest::new(480);
+        held.fermata = true;
+        let lines = rest(held);
+        assert!(lines.contains(&"<fermata/>".to_string()));
+        assert!(!rest(Rest::new(480)).contains(&"<notations>".to_string()));
+    }
+
+    #[test]
+    fn measure_rest() {
+        let mut whole = Rest::new(1440);
+        whole.measure = true;
+        let lines = rest(whole);
+        assert_eq!(lines[1], r#"<rest measure="yes"/>"#);
+        assert!(lines.contains(&"<duration>1440</duration>".to_string()));
+        assert!(!lines.iter().any(|line| line.starts_with("<type>")));
+    }
+
+    #[test]
+    fn parts_only_for_staves_with_voices() {
+        let mut xml = MusicXml::new();
+        xml.score(&[Collector::new(1)]);
+        assert!(xml.lines.contains(&r#"  <part id="P2">"#.to_string()));
+        assert!(!xml.lines.iter().any(|line| line.contains(r#""P1""#)));
+    }
+
+    #[test]
+    fn metronome_in_its_note() {
+        let mut xml = MusicXml::new();
+        xml.lines.clear();
+        xml.tempo(&Tempo {
+            words: String::new(),
+            change: Change::Set(120),
+            beat: Some((1440, 40)),
+        });
+        let lines = xml.lines.iter().map(|line| line.trim()).collect::<Vec<_>>();
+        assert!(lines.contains(&"<beat-unit>half</beat-unit>"));
+        assert!(lines.contains(&"<beat-unit-dot/>"));
+        assert!(lines.contains(&"<per-minute>40</per-minute>"));
+        assert!(lines.contains(&r#"<sound tempo="120"/>"#));
+    }
+}
@@ -24,6 +24,12 @@ impl Matrix {
         }
     }
 
+    // Average length a unit vector gets scaled to, used for line widths
+    pub fn scale(&self) -> Fixed {
+        let det = f64::from(self.get(0, 0) * self.get(1, 1) - self.get(0, 1) * self.get(1, 0));
+        Fixed::new(det.abs().sqrt())
+    }
+
     pub fn transform<A: Into<Fixed> + Copy>(&self, x: A, y: A) -> (Fixed, Fixed) {
         let xp = Vector::new(x, y, 1) * &self.data[0];
         let yp = Vector::new(x, y, 1) * &self.data[1];
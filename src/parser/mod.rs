@@ -1,35 +1,51 @@
 mod matrix;
 mod vector;
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
+use failure::Fail;
 use lopdf::content::Content;
 use lopdf::{Object, Stream};
 
 use matrix::Matrix;
 use vector::Vector;
 
-use crate::common::{HorzLine, Line, Point, Polygon, PolygonRes, Quadrangle, VertLine};
+use crate::common::{
+    Curve, Fixed, HorzLine, Line, Point, Polygon, PolygonRes, Quadrangle, Text, VertLine,
+};
 
-#[derive(Debug, Fail)]
+#[derive(Debug)]
 pub enum Error {
-    #[fail(display = "invalid dict")]
     Dict,
-    #[fail(display = "invalid object")]
     Object,
-    #[fail(display = "invalid operand")]
     Operand,
-    #[fail(display = "no content")]
     NoContent,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Dict => write!(f, "invalid dict"),
+            Error::Object => write!(f, "invalid object"),
+            Error::Operand => write!(f, "invalid operand"),
+            Error::NoContent => write!(f, "no content"),
+        }
+    }
+}
+
+// Written out rather than derived, failure_derive puts its impls in a nested scope
+impl Fail for Error {}
+
 #[derive(Debug)]
 pub struct Parser {
     pub horz_lines: HashSet<HorzLine>,
     pub vert_lines: HashSet<VertLine>,
     pub quadras: HashSet<Quadrangle>,
     pub lines: HashSet<Line>,
+    pub curves: HashSet<Curve>,
     pub objects: Vec<crate::common::Object>,
+    pub texts: Vec<Text>,
 }
 
 impl Parser {
@@ -61,14 +77,20 @@ impl Parser {
         let mut vert_lines = HashSet::new();
         let mut lines = HashSet::new();
         let mut quadras = HashSet::new();
+        let mut curves = HashSet::new();
+        let mut texts = vec![];
         let mut tm = Matrix::identity();
         let mut matrix = Matrix::identity();
+        let mut line_width = Fixed::new(1);
 
         let mut stack = vec![];
-        stack.push(matrix);
+        stack.push((matrix, line_width));
         let mut heads = vec![];
 
         let mut active = false;
+        let mut font_size = Fixed::new(1);
+        // Size each music glyph is drawn at, by index into the objects
+        let mut sizes = vec![];
 
         let mut polygon: Option<Polygon> = None;
 
@@ -84,10 +106,18 @@ impl Parser {
                                 PolygonRes::HorzLine(v) => horz_lines.insert(v),
                                 PolygonRes::Line(l) => lines.insert(l),
                                 PolygonRes::Quadrangle(l) => quadras.insert(l),
+                                PolygonRes::Dot(p) => {
+                                    heads.push(crate::common::Object::new(
+                                        crate::common::Type::Dot,
+                                        p,
+                                    ));
+                                    true
+                                }
+                                PolygonRes::Curve(c) => curves.insert(c),
                                 PolygonRes::Empty => true,
                             };
                         }
-                        polygon = Some(Polygon::new(x, y));
+                        polygon = Some(Polygon::new(x, y, line_width * matrix.scale()));
                     }
                     _ => return Err(failure::Error::from(Error::Operand)),
                 },
@@ -102,8 +132,15 @@ impl Parser {
                     _ => return Err(failure::Error::from(Error::Operand)),
                 },
                 "c" => match *Self::read_num_slice(&op.operands)?.as_slice() {
-                    [_x1, _y1, _x2, _y2, _x3, _y3] => {
+                    [x1, y1, x2, y2, x3, y3] => {
                         // Curve
+                        let (x1, y1) = matrix.transform(x1, y1);
+                        let (x2, y2) = matrix.transform(x2, y2);
+                        let (x3, y3) = matrix.transform(x3, y3);
+
+                        if let Some(p) = &mut polygon {
+                            p.curve(Point::new(x1, y1), Point::new(x2, y2), Point::new(x3, y3));
+                        }
                     }
                     _ => return Err(failure::Error::from(Error::Operand)),
                 },
@@ -115,6 +152,11 @@ impl Parser {
                             PolygonRes::HorzLine(v) => horz_lines.insert(v),
                             PolygonRes::Line(l) => lines.insert(l),
                             PolygonRes::Quadrangle(l) => quadras.insert(l),
+                            PolygonRes::Dot(p) => {
+                                heads.push(crate::common::Object::new(crate::common::Type::Dot, p));
+                                true
+                            }
+                            PolygonRes::Curve(c) => curves.insert(c),
                             PolygonRes::Empty => true,
                         };
                     }
@@ -122,7 +164,7 @@ impl Parser {
                 }
                 "q" => {
                     // Save the current graphics state on the graphics state stack
-                    stack.push(matrix);
+                    stack.push((matrix, line_width));
                 }
                 "gs" => {
                     // Set the specified parameters in the graphics state
@@ -146,7 +188,9 @@ impl Parser {
                 }
                 "Q" => {
                     // Restore the graphics state by removing the most recently saved state from the stack and making it the current state
-                    matrix = stack.pop().unwrap();
+                    let (m, w) = stack.pop().unwrap();
+                    matrix = m;
+                    line_width = w;
                 }
                 "SCN" => {
                     // Color::??
@@ -159,6 +203,12 @@ impl Parser {
                 "w" => {
                     // Set the line width in the graphics state
                     // [5]
+                    match *Self::read_num_slice(&op.operands)?.as_slice() {
+                        [w] => {
+                            line_width = Fixed::new(w);
+                        }
+                        _ => return Err(failure::Error::from(Error::Operand)),
+                    }
                 }
                 "J" => {
                     // Set the line cap style in the graphics state
@@ -211,6 +261,9 @@ impl Parser {
                         }
                         _ => panic!("wrong"),
                     }
+                    if let [size] = *Self::read_num_slice(&op.operands[1..])?.as_slice() {
+                        font_size = Fixed::new(size);
+                    }
                 }
                 "Tm" => {
                     // Text Matrix
@@ -237,7 +290,9 @@ impl Parser {
                     // [()]
                     match &mut op.operands[0] {
                         Object::String(vec, _format) => {
-                            use crate::common::Type;
+                            use crate::common::{
+                                Accidental, Articulation, Direction, Dynamic, Sign, Type,
+                            };
                             // 1: C
                             // 2: &
                             // 3: rectangle
@@ -258,18 +313,81 @@ impl Parser {
                             let (x, y) = tm.transform(0, 0);
                             let (x, y) = matrix.transform(x, y);
                             let t = match vec.as_slice() {
+                                [0, 1] => Some(Type::Clef(Sign::C, 0)),
+                                [0, 2] => Some(Type::Clef(Sign::G, 0)),
                                 [0, 7] => Some(Type::Head(4)),
-                                [0, 8] => Some(Type::Wing(8)),
+                                [0, 8] => Some(Type::Wing(8, Direction::Up)),
                                 [0, 9] => Some(Type::Rest(8)),
                                 [0, 10] => Some(Type::Head(1)),
+                                [0, 11] => Some(Type::Accidental(Accidental::Sharp)),
+                                [0, 13] => Some(Type::Wing(8, Direction::Down)),
+                                [0, 14] => Some(Type::Accidental(Accidental::Flat)),
                                 [0, 16] => Some(Type::Head(2)),
+                                [0, 17] => Some(Type::Wing(16, Direction::Up)),
+                                [0, 18] => Some(Type::Wing(16, Direction::Down)),
                                 [0, 3] => Some(Type::Rest(1)),
-                                _ => None,
+                                [0, 15] => Some(Type::Rest(4)),
+                                [0, 19] => Some(Type::Rest(16)),
+                                // Fonts embedded as a whole keep the Sonata encoding
+                                b"&" => Some(Type::Clef(Sign::G, 0)),
+                                b"V" => Some(Type::Clef(Sign::G, -1)),
+                                b"?" => Some(Type::Clef(Sign::F, 0)),
+                                b"B" => Some(Type::Clef(Sign::C, 0)),
+                                b"W" => Some(Type::Head(0)),
+                                b"w" => Some(Type::Head(1)),
+                                [0xfa] => Some(Type::Head(2)),
+                                [0xcf] => Some(Type::Head(4)),
+                                b"#" => Some(Type::Accidental(Accidental::Sharp)),
+                                b"b" => Some(Type::Accidental(Accidental::Flat)),
+                                b"n" => Some(Type::Accidental(Accidental::Natural)),
+                                b"(" | b")" => Some(Type::Parenthesis),
+                                b"." => Some(Type::Dot),
+                                [d @ b'0'..=b'9'] => Some(Type::Digit(d - b'0')),
+                                b"c" => Some(Type::Time(4, 4)),
+                                b"j" => Some(Type::Wing(8, Direction::Up)),
+                                b"J" => Some(Type::Wing(8, Direction::Down)),
+                                b"r" => Some(Type::Wing(16, Direction::Up)),
+                                b"R" => Some(Type::Wing(16, Direction::Down)),
+                                b"C" => Some(Type::Time(2, 2)),
+                                b"%" => Some(Type::Segno),
+                                [0xde] => Some(Type::Coda),
+                                b"P" => Some(Type::Dynamic(Dynamic::Mp)),
+                                b"F" => Some(Type::Dynamic(Dynamic::Mf)),
+                                [0xb8] => Some(Type::Dynamic(Dynamic::Ppp)),
+                                [0xb9] => Some(Type::Dynamic(Dynamic::Pp)),
+                                [0xc4] => Some(Type::Dynamic(Dynamic::Ff)),
+                                [0xec] => Some(Type::Dynamic(Dynamic::Fff)),
+                                [0xa7] => Some(Type::Dynamic(Dynamic::Sfz)),
+                                [0xce] => Some(Type::Rest(4)),
+                                [0xe4] => Some(Type::Rest(8)),
+                                [0xc5] => Some(Type::Rest(16)),
+                                [0xa8] => Some(Type::Rest(32)),
+                                // Staccato uses the same dot as augmentation
+                                b">" => Some(Type::Articulation(Articulation::Accent)),
+                                b"^" | b"v" => Some(Type::Articulation(Articulation::Marcato)),
+                                b"-" => Some(Type::Articulation(Articulation::Tenuto)),
+                                b"'" | [0xae] => {
+                                    Some(Type::Articulation(Articulation::Staccatissimo))
+                                }
+                                b"U" | b"u" => Some(Type::Articulation(Articulation::Fermata)),
+                                // Dynamics spelled out letter by letter
+                                etc => std::str::from_utf8(etc)
+                                    .ok()
+                                    .and_then(Dynamic::parse)
+                                    .map(Type::Dynamic),
                             };
-                            if let Some(t) = t {
-                                if active {
+                            if active {
+                                if let Some(t) = t {
+                                    sizes.push((
+                                        heads.len(),
+                                        font_size * tm.scale() * matrix.scale(),
+                                    ));
                                     heads.push(crate::common::Object::new(t, Point::new(x, y)))
                                 }
+                            } else if vec.first() != Some(&0) {
+                                // Single byte encodings are taken as Latin-1
+                                let text = vec.iter().map(|&b| char::from(b)).collect();
+                                texts.push(Text::new(text, Point::new(x, y)));
                             }
                         }
                         etc => {
@@ -294,10 +412,26 @@ impl Parser {
                 PolygonRes::HorzLine(v) => horz_lines.insert(v),
                 PolygonRes::Line(l) => lines.insert(l),
                 PolygonRes::Quadrangle(l) => quadras.insert(l),
+                PolygonRes::Dot(p) => {
+                    heads.push(crate::common::Object::new(crate::common::Type::Dot, p));
+                    true
+                }
+                PolygonRes::Curve(c) => curves.insert(c),
                 PolygonRes::Empty => true,
             };
         }
 
+        // Glyphs well under the size most of them share are cue-sized
+        let mut counts: HashMap<Fixed, usize> = HashMap::new();
+        for (_, size) in sizes.iter() {
+            *counts.entry(*size).or_default() += 1;
+        }
+        if let Some((&normal, _)) = counts.iter().max_by_key(|(size, count)| (**count, **size)) {
+            for (index, size) in sizes {
+                heads[index].small = size < normal * 0.85;
+            }
+        }
+
         stream.set_plain_content(res.encode().unwrap());
         stream.compress();
         Ok(Self {
@@ -305,7 +439,9 @@ impl Parser {
             vert_lines,
             lines,
             quadras,
+            curves,
             objects: heads,
+            texts,
         })
     }
 }
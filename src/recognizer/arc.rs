@@ -0,0 +1,90 @@
+use crate::common::{Curve, Fixed, Object};
+
+// Ties and slurs, from their left end to their right end
+#[derive(Debug, Clone)]
+pub struct Arc {
+    pub x1: Fixed,
+    pub y1: Fixed,
+    pub x2: Fixed,
+    pub y2: Fixed,
+}
+
+impl Arc {
+    pub fn new(curve: &Curve) -> Option<Self> {
+        let [from, _, _, to] = &curve.points;
+        let (left, right) = if from.x < to.x {
+            (from, to)
+        } else {
+            (to, from)
+        };
+        // Steeper than 45 degrees is not an arc
+        if right.x - left.x <= (right.y - left.y).abs() {
+            return None;
+        }
+        Some(Self {
+            x1: left.x,
+            y1: left.y,
+            x2: right.x,
+            y2: right.y,
+        })
+    }
+
+    // Arcs leave just right of the head, or above or below its centre
+    pub fn starts_at(&self, head: &Object, spacing: Fixed) -> Option<Fixed> {
+        let (dx, dy) = (self.x1 - head.point.x, (self.y1 - head.point.y).abs());
+        if Fixed::new(0) <= dx && dx <= spacing * 2 && dy <= spacing * 2 {
+            Some(dx + dy)
+        } else {
+            None
+        }
+    }
+
+    pub fn ends_at(&self, head: &Object, spacing: Fixed) -> Option<Fixed> {
+        let (dx, dy) = (head.point.x - self.x2, (self.y2 - head.point.y).abs());
+        if dx.abs() <= spacing && dy <= spacing * 2 {
+            Some(dx.abs() + dy)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Point, Type};
+
+    fn curve(points: [(i64, i64); 4]) -> Curve {
+        let [a, b, c, d] = points;
+        Curve {
+            points: [
+                Point::new(a.0, a.1),
+                Point::new(b.0, b.1),
+                Point::new(c.0, c.1),
+                Point::new(d.0, d.1),
+            ],
+        }
+    }
+
+    fn head(x: i64, y: i64) -> Object {
+        Object::new(Type::Head(4), Point::new(x, y))
+    }
+
+    #[test]
+    fn arcs_run_left_to_right() {
+        let arc = Arc::new(&curve([(80, 112), (70, 118), (40, 118), (30, 114)])).unwrap();
+        assert_eq!((arc.x1, arc.y1), (Fixed::new(30), Fixed::new(114)));
+        assert_eq!((arc.x2, arc.y2), (Fixed::new(80), Fixed::new(112)));
+        assert!(Arc::new(&curve([(30, 100), (32, 110), (34, 120), (36, 130)])).is_none());
+    }
+
+    #[test]
+    fn ends_by_the_heads() {
+        let spacing = Fixed::new(10);
+        let arc = Arc::new(&curve([(30, 114), (40, 118), (70, 118), (80, 112)])).unwrap();
+        assert!(arc.starts_at(&head(25, 110), spacing).is_some());
+        assert!(arc.starts_at(&head(35, 110), spacing).is_none());
+        assert!(arc.ends_at(&head(85, 110), spacing).is_some());
+        assert!(arc.ends_at(&head(85, 140), spacing).is_none());
+    }
+}
@@ -1,30 +1,137 @@
 mod stems;
 
-use crate::common::{Fixed, Object, VertLine};
+pub use stems::Stems;
+
+use crate::common::{Fixed, Object, Text};
 use std::cmp::Ordering;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Barline {
+    Single,
+    Double,
+    Final,
+    StartRepeat,
+    EndRepeat,
+    // End and start repeat back to back
+    Repeat,
+    Dashed,
+}
+
+impl Barline {
+    // Side closing the measure on the left of the barline
+    pub fn right(self) -> Barline {
+        match self {
+            Barline::StartRepeat => Barline::Single,
+            Barline::Repeat => Barline::EndRepeat,
+            etc => etc,
+        }
+    }
+
+    // Side opening the measure on the right of the barline
+    pub fn left(self) -> Barline {
+        match self {
+            Barline::StartRepeat | Barline::Repeat => Barline::StartRepeat,
+            _ => Barline::Single,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Bar {
+    // Right edge of the barline, the measure starts there
     pub x: Fixed,
-    pub high: Vec<u8>,
-    pub low: Vec<u8>,
     pub store: Vec<Object>,
-    pub stems: stems::Stems,
+    pub texts: Vec<Text>,
+    pub stems: Stems,
+    // Thin and thick lines making up the barline, by x
+    lines: Vec<(Fixed, bool)>,
+    dots_left: bool,
+    dots_right: bool,
+    dashed: bool,
+    // Barline ending the system, no measure follows
+    pub closing: bool,
 }
 
 impl Bar {
     pub fn new(x: Fixed) -> Self {
         Self {
             x,
-            high: vec![],
-            low: vec![],
             store: vec![],
+            texts: vec![],
             stems: Default::default(),
+            lines: vec![],
+            dots_left: false,
+            dots_right: false,
+            dashed: false,
+            closing: false,
         }
     }
 
-    pub fn debug(&self) {
-        println!("Bar x: {:?}, objects: {}", self.x, self.store.len());
+    pub fn dashed(x: Fixed) -> Self {
+        let mut bar = Self::new(x);
+        bar.dashed = true;
+        bar
+    }
+
+    pub fn is_near(&self, x: Fixed, tolerance: Fixed) -> bool {
+        (self.x - x).abs() <= tolerance
+            || self
+                .lines
+                .iter()
+                .any(|(line, _)| (*line - x).abs() <= tolerance)
+    }
+
+    // Segments of the same line across several staves count once
+    pub fn push_line(&mut self, x: Fixed, thick: bool, tolerance: Fixed) {
+        match self
+            .lines
+            .iter_mut()
+            .find(|(line, _)| (*line - x).abs() <= tolerance)
+        {
+            Some(line) => line.1 |= thick,
+            None => {
+                self.lines.push((x, thick));
+                self.lines.sort_by_key(|(line, _)| *line);
+            }
+        }
+        self.x = self.lines.last().unwrap().0;
+    }
+
+    // Repeat dots lie in the two middle spaces just off a barline with a thick line
+    pub fn put_dot(&mut self, x: Fixed, reach: Fixed) -> bool {
+        let (first, last) = match (self.lines.first(), self.lines.last()) {
+            (Some(first), Some(last)) if self.lines.iter().any(|(_, thick)| *thick) => {
+                (first.0, last.0)
+            }
+            _ => return false,
+        };
+        if first - reach <= x && x < first {
+            self.dots_left = true;
+        } else if last < x && x <= last + reach {
+            self.dots_right = true;
+        } else {
+            return false;
+        }
+        true
+    }
+
+    pub fn style(&self) -> Barline {
+        if self.dashed {
+            return Barline::Dashed;
+        }
+        let thick = self
+            .lines
+            .iter()
+            .map(|(_, thick)| *thick)
+            .collect::<Vec<_>>();
+        match (thick.as_slice(), self.dots_left, self.dots_right) {
+            (_, true, true) => Barline::Repeat,
+            (_, true, false) => Barline::EndRepeat,
+            (_, false, true) => Barline::StartRepeat,
+            ([false, true], false, false) => Barline::Final,
+            ([_, _, ..], false, false) => Barline::Double,
+            _ => Barline::Single,
+        }
     }
 }
 
@@ -47,3 +154,43 @@ impl PartialEq for Bar {
 }
 
 impl Eq for Bar {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bar(lines: &[(i64, bool)]) -> Bar {
+        let mut bar = Bar::new(Fixed::new(lines[0].0));
+        for (x, thick) in lines {
+            bar.push_line(Fixed::new(*x), *thick, Fixed::new(1));
+        }
+        bar
+    }
+
+    #[test]
+    fn styles_from_lines() {
+        assert_eq!(bar(&[(100, false)]).style(), Barline::Single);
+        assert_eq!(bar(&[(100, false), (104, false)]).style(), Barline::Double);
+        assert_eq!(bar(&[(100, false), (104, true)]).style(), Barline::Final);
+        assert_eq!(Bar::dashed(Fixed::new(100)).style(), Barline::Dashed);
+        // Segments of one line across staves
+        let bar = bar(&[(100, false), (100, false), (104, true)]);
+        assert_eq!(bar.x, Fixed::new(104));
+        assert_eq!(bar.style(), Barline::Final);
+    }
+
+    #[test]
+    fn repeats_from_dots() {
+        let reach = Fixed::new(15);
+        let mut end = bar(&[(100, false), (104, true)]);
+        assert!(end.put_dot(Fixed::new(92), reach));
+        assert_eq!(end.style(), Barline::EndRepeat);
+        let mut start = bar(&[(100, true), (104, false)]);
+        assert!(start.put_dot(Fixed::new(112), reach));
+        assert_eq!(start.style(), Barline::StartRepeat);
+        assert!(start.put_dot(Fixed::new(92), reach));
+        assert_eq!(start.style(), Barline::Repeat);
+        // Thin barlines take no dots
+        assert!(!bar(&[(100, false)]).put_dot(Fixed::new(92), reach));
+    }
+}
@@ -1,9 +1,10 @@
-use crate::common::{Fixed, Object, VertLine};
+use crate::common::{Direction, Fixed, Object, VertLine};
 
 #[derive(Default, Debug)]
 struct Store {
     stems: Vec<VertLine>,
-    current: Option<VertLine>,
+    // Stems already holding heads, open to the rest of their chord
+    current: Vec<VertLine>,
 }
 
 impl Store {
@@ -12,84 +13,183 @@ impl Store {
     }
 
     fn sort(&mut self) {
-        assert!(self.current.is_none());
-        self.stems.sort_by(|a, b| b.x.cmp(&a.x));
+        assert!(self.current.is_empty());
+        self.stems.sort_by_key(|stem| std::cmp::Reverse(stem.x));
     }
 
     fn get_head_size(&self, obj: &Object) -> Option<Fixed> {
         self.stems.last().map(|stem| stem.x - obj.point.x)
     }
 
+    // Heads sit left of the stem, except the upper note of a second on an up stem
+    // which sits right of it
     fn attachable(stem: &VertLine, obj: &Object, flexibility: Fixed, width: Option<Fixed>) -> bool {
-        (obj.point.x <= stem.x && width.map_or(true, |w| obj.point.x + w >= stem.x))
+        (obj.point.x <= stem.x + flexibility && width.is_none_or(|w| obj.point.x + w >= stem.x))
             && (obj.point.y > stem.y1 - flexibility && obj.point.y < stem.y2 + flexibility)
     }
 
-    fn attach(&mut self, obj: &Object, flexibility: Fixed, width: Option<Fixed>) -> bool {
-        let mut res = false;
+    // Flags hang from the free end of the stem
+    fn flaggable(stem: &VertLine, obj: &Object, direction: Direction, flexibility: Fixed) -> bool {
+        let end = match direction {
+            Direction::Up => stem.y1,
+            Direction::Down => stem.y2,
+        };
+        (obj.point.x - stem.x).abs() <= flexibility && (obj.point.y - end).abs() <= flexibility
+    }
+
+    fn attach_flag(
+        &self,
+        obj: &Object,
+        direction: Direction,
+        flexibility: Fixed,
+    ) -> Option<VertLine> {
+        self.current
+            .iter()
+            .chain(self.stems.iter())
+            .find(|stem| Self::flaggable(stem, obj, direction, flexibility))
+            .cloned()
+    }
+
+    fn attach(
+        &mut self,
+        obj: &Object,
+        flexibility: Fixed,
+        width: Option<Fixed>,
+    ) -> Option<VertLine> {
         // Try current
-        if let Some(current) = &self.current {
-            println!("T existing {:?} + {:?}", current, obj);
-            if Self::attachable(current, obj, flexibility, width) {
-                println!("Attached existing {:?} + {:?}", current, obj);
-                res = true;
-            }
+        if let Some(current) = self
+            .current
+            .iter()
+            .find(|stem| Self::attachable(stem, obj, flexibility, width))
+        {
+            return Some(current.clone());
         }
-        // Try new one
-        if let Some(new) = self.stems.last() {
-            println!("T new {:?} + {:?}", new, obj);
-            if Self::attachable(new, obj, flexibility, width) {
-                println!("Attached new {:?} + {:?}", new, obj);
-                self.current = self.stems.pop();
-                res = true;
-            }
-        }
-        return res;
+        // Try new ones, another voice may own the nearest stem
+        let reach = width.unwrap_or(flexibility * 4);
+        let found = self
+            .stems
+            .iter()
+            .rev()
+            .take_while(|stem| stem.x - obj.point.x <= reach)
+            .position(|stem| Self::attachable(stem, obj, flexibility, width))?;
+        let new = self.stems.remove(self.stems.len() - 1 - found);
+        self.current.push(new.clone());
+        Some(new)
     }
 }
 
 #[derive(Default, Debug)]
 pub struct Stems {
-    high: Store,
-    low: Store,
-    mid: Store,
+    staves: Vec<Store>,
 }
 
 impl Stems {
     pub fn sort(&mut self) {
-        self.high.sort();
-        self.low.sort();
-        self.mid.sort();
+        for store in self.staves.iter_mut() {
+            store.sort();
+        }
     }
 
-    pub fn push_high(&mut self, stem: &VertLine) {
-        self.high.push(stem);
+    pub fn push(&mut self, staff: usize, stem: &VertLine) {
+        if self.staves.len() <= staff {
+            self.staves.resize_with(staff + 1, Default::default);
+        }
+        self.staves[staff].push(stem);
     }
 
-    pub fn push_low(&mut self, stem: &VertLine) {
-        self.low.push(stem);
+    pub fn get_head_size(&self, obj: &Object) -> Option<Fixed> {
+        self.staves
+            .iter()
+            .filter_map(|store| store.get_head_size(obj))
+            .min()
+            .map(|res| res * 1.1)
     }
 
-    pub fn push_mid(&mut self, stem: &VertLine) {
-        self.mid.push(stem);
+    pub fn attach_flag(
+        &self,
+        obj: &Object,
+        direction: Direction,
+        flexibility: Fixed,
+    ) -> Option<VertLine> {
+        self.staves
+            .iter()
+            .find_map(|store| store.attach_flag(obj, direction, flexibility))
     }
 
-    pub fn get_head_size(&self, obj: &Object) -> Option<Fixed> {
-        [
-            self.high.get_head_size(obj),
-            self.low.get_head_size(obj),
-            self.mid.get_head_size(obj),
-        ]
-        .iter()
-        .flatten()
-        .min()
-        .map(|res| *res * 1.1)
-    }
-
-    pub fn attach(&mut self, obj: &Object, flexibility: Fixed, width: Option<Fixed>) -> bool {
-        let high = self.high.attach(obj, flexibility, width);
-        let low = self.low.attach(obj, flexibility, width);
-        let mid = self.mid.attach(obj, flexibility, width);
-        high || low || mid
+    pub fn attach(
+        &mut self,
+        obj: &Object,
+        flexibility: Fixed,
+        width: Option<Fixed>,
+    ) -> Option<VertLine> {
+        self.staves
+            .iter_mut()
+            .find_map(|store| store.attach(obj, flexibility, width))
+    }
+
+    // Whether a stem ends on the block, as stems do on a beam
+    pub fn ends_on(&self, x1: Fixed, x2: Fixed, y1: Fixed, y2: Fixed, tolerance: Fixed) -> bool {
+        let on = |y: Fixed| y1 - tolerance <= y && y <= y2 + tolerance;
+        self.staves
+            .iter()
+            .flat_map(|store| store.stems.iter().chain(store.current.iter()))
+            .any(|stem| {
+                x1 - tolerance <= stem.x && stem.x <= x2 + tolerance && (on(stem.y1) || on(stem.y2))
+            })
+    }
+
+    // Heads at the lower end of the stem have it pointing up
+    pub fn direction(stem: &VertLine, obj: &Object) -> Direction {
+        if stem.y2 - obj.point.y < obj.point.y - stem.y1 {
+            Direction::Up
+        } else {
+            Direction::Down
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Point, Type};
+
+    fn head(x: i64, y: i64) -> Object {
+        Object::new(Type::Head(4), Point::new(x, y))
+    }
+
+    fn wing(x: i64, y: i64, direction: Direction) -> Object {
+        Object::new(Type::Wing(8, direction), Point::new(x, y))
+    }
+
+    fn stems() -> Stems {
+        let mut stems = Stems::default();
+        stems.push(0, &VertLine::new(20, 80, 120));
+        stems.push(0, &VertLine::new(50, 120, 160));
+        stems.sort();
+        stems
+    }
+
+    #[test]
+    fn flags_at_the_free_end() {
+        let stems = stems();
+        let flexibility = Fixed::new(5);
+        let up = stems.attach_flag(&wing(21, 82, Direction::Up), Direction::Up, flexibility);
+        assert_eq!(up, Some(VertLine::new(20, 80, 120)));
+        let down = stems.attach_flag(
+            &wing(51, 158, Direction::Down),
+            Direction::Down,
+            flexibility,
+        );
+        assert_eq!(down, Some(VertLine::new(50, 120, 160)));
+        // Halfway up the stem is no end of it
+        let middle = wing(21, 100, Direction::Up);
+        assert_eq!(stems.attach_flag(&middle, Direction::Up, flexibility), None);
+    }
+
+    #[test]
+    fn direction_from_the_head_end() {
+        let stem = VertLine::new(20, 80, 120);
+        assert_eq!(Stems::direction(&stem, &head(14, 119)), Direction::Up);
+        assert_eq!(Stems::direction(&stem, &head(26, 81)), Direction::Down);
     }
 }
@@ -0,0 +1,95 @@
+use crate::common::{Fixed, Quadrangle, VertLine};
+
+#[derive(Debug, Clone)]
+pub struct Beam {
+    pub x1: Fixed,
+    pub x2: Fixed,
+    // Centre line at both ends
+    pub y1: Fixed,
+    pub y2: Fixed,
+    pub thickness: Fixed,
+}
+
+impl Beam {
+    // Beams are parallelograms with both short sides upright
+    pub fn new(quadra: &Quadrangle) -> Option<Self> {
+        let mut points = quadra.points.to_vec();
+        points.sort_by_key(|point| (point.x, point.y));
+        let (left, right) = (&points[0..2], &points[2..4]);
+        let thickness = left[1].y - left[0].y;
+        let tolerance = thickness / 4;
+        if (left[0].x - left[1].x).abs() > tolerance
+            || (right[0].x - right[1].x).abs() > tolerance
+            || ((right[1].y - right[0].y) - thickness).abs() > tolerance
+            || right[0].x - left[0].x < thickness * 2
+        {
+            return None;
+        }
+        let beam = Self {
+            x1: left[0].x,
+            x2: right[0].x,
+            y1: left[0].y + thickness / 2,
+            y2: right[0].y + thickness / 2,
+            thickness,
+        };
+        // Steeper than 45 degrees is not a beam
+        if (beam.y2 - beam.y1).abs() > beam.x2 - beam.x1 {
+            None
+        } else {
+            Some(beam)
+        }
+    }
+
+    pub fn y_at(&self, x: Fixed) -> Fixed {
+        self.y1 + (self.y2 - self.y1) * ((x - self.x1) / (self.x2 - self.x1))
+    }
+
+    // Stems run through every beam stacked on them
+    pub fn touches(&self, stem: &VertLine) -> bool {
+        let y = self.y_at(stem.x);
+        self.x1 - self.thickness <= stem.x
+            && stem.x <= self.x2 + self.thickness
+            && stem.y1 - self.thickness <= y
+            && y <= stem.y2 + self.thickness
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Point;
+
+    fn quadra(points: [(i64, i64); 4]) -> Quadrangle {
+        let [a, b, c, d] = points;
+        Quadrangle {
+            points: [
+                Point::new(a.0, a.1),
+                Point::new(b.0, b.1),
+                Point::new(c.0, c.1),
+                Point::new(d.0, d.1),
+            ],
+        }
+    }
+
+    #[test]
+    fn slanted_beam() {
+        let beam = Beam::new(&quadra([(10, 100), (60, 90), (60, 95), (10, 105)])).unwrap();
+        assert_eq!((beam.x1, beam.x2), (Fixed::new(10), Fixed::new(60)));
+        assert_eq!(beam.y1, Fixed::new(102.5));
+        assert_eq!(beam.y2, Fixed::new(92.5));
+        assert_eq!(beam.y_at(Fixed::new(35)), Fixed::new(97.5));
+        assert!(beam.touches(&VertLine::new(60, 92, 130)));
+        assert!(!beam.touches(&VertLine::new(60, 110, 130)));
+        assert!(!beam.touches(&VertLine::new(80, 80, 130)));
+    }
+
+    #[test]
+    fn not_beams() {
+        // Too steep
+        assert!(Beam::new(&quadra([(10, 100), (15, 80), (15, 85), (10, 105)])).is_none());
+        // Short sides not upright
+        assert!(Beam::new(&quadra([(10, 100), (60, 100), (64, 105), (14, 105)])).is_none());
+        // Not as thick at both ends
+        assert!(Beam::new(&quadra([(10, 100), (60, 100), (60, 110), (10, 105)])).is_none());
+    }
+}
@@ -0,0 +1,87 @@
+use crate::common::{Fixed, Sign};
+use crate::recognizer::{Pitch, Staff};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clef {
+    pub sign: Sign,
+    // Staff line the clef is anchored to, counted from the bottom line as 1
+    pub line: i32,
+    pub octave: i8,
+}
+
+impl Clef {
+    pub fn new(sign: Sign, line: i32, octave: i8) -> Self {
+        Self { sign, line, octave }
+    }
+
+    pub fn treble() -> Self {
+        Self::new(Sign::G, 2, 0)
+    }
+
+    // The glyph origin sits on the line the clef names
+    pub fn locate(sign: Sign, octave: i8, staff: &Staff, y: Fixed) -> Self {
+        let line = Self::position(staff, y).div_euclid(2) + 1;
+        Self::new(sign, line, octave)
+    }
+
+    // Half spaces above the bottom line of the staff
+    pub fn position(staff: &Staff, y: Fixed) -> i32 {
+        ((staff.bottom() - y) / staff.spacing * 2.).round() as i32
+    }
+
+    pub fn pitch(&self, staff: &Staff, y: Fixed) -> Pitch {
+        let anchor = match self.sign {
+            Sign::G => 4,
+            Sign::F => -4,
+            Sign::C => 0,
+        };
+        Pitch::new(
+            anchor + Self::position(staff, y) - (self.line - 1) * 2 + i32::from(self.octave) * 7,
+            0,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn staff() -> Staff {
+        Staff::new(
+            Fixed::new(0),
+            Fixed::new(500),
+            Fixed::new(100),
+            5,
+            Fixed::new(10),
+        )
+    }
+
+    #[test]
+    fn treble_lines() {
+        let clef = Clef::treble();
+        // Bottom line E4, top line F5
+        assert_eq!(clef.pitch(&staff(), Fixed::new(140)), Pitch::new(2, 0));
+        assert_eq!(clef.pitch(&staff(), Fixed::new(100)), Pitch::new(10, 0));
+        // Middle C on the first ledger line below
+        assert_eq!(clef.pitch(&staff(), Fixed::new(150)), Pitch::new(0, 0));
+    }
+
+    #[test]
+    fn locate_on_its_line() {
+        let bass = Clef::locate(Sign::F, 0, &staff(), Fixed::new(110));
+        assert_eq!(bass, Clef::new(Sign::F, 4, 0));
+        // Bottom line G2
+        assert_eq!(bass.pitch(&staff(), Fixed::new(140)), Pitch::new(-10, 0));
+        let alto = Clef::locate(Sign::C, 0, &staff(), Fixed::new(120));
+        assert_eq!(alto, Clef::new(Sign::C, 3, 0));
+        assert_eq!(alto.pitch(&staff(), Fixed::new(120)), Pitch::new(0, 0));
+        let tenor = Clef::locate(Sign::C, 0, &staff(), Fixed::new(110));
+        assert_eq!(tenor.pitch(&staff(), Fixed::new(110)), Pitch::new(0, 0));
+    }
+
+    #[test]
+    fn octave_clef() {
+        let clef = Clef::locate(Sign::G, -1, &staff(), Fixed::new(130));
+        assert_eq!(clef.pitch(&staff(), Fixed::new(140)), Pitch::new(-5, 0));
+    }
+}
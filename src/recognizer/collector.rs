@@ -1,96 +1,743 @@
-use crate::common::Fixed;
+use std::collections::HashMap;
+
+use crate::common::{Accidental, Articulation, Direction, Dynamic, Fixed, Point};
+use crate::recognizer::{
+    Barline, Clef, Harmony, Key, Lyric, Mark, Pitch, Staff, Tempo, TimeSignature, Tuplet, Wedge,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slur {
+    Start,
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grace {
+    // Slashed through the stem, played as short as possible
+    Acciaccatura,
+    Appoggiatura,
+}
+
+#[derive(Debug)]
+pub struct Chord {
+    pub tones: Vec<Pitch>,
+    // Sounding length, after dots and tuplets
+    pub len: i32,
+    pub dots: u8,
+    pub tuplet: Option<Tuplet>,
+    pub stem: Option<Direction>,
+    // Tones held over into the next chord
+    pub tied: Vec<Pitch>,
+    pub slurs: Vec<Slur>,
+    pub dynamic: Option<Dynamic>,
+    pub wedges: Vec<Wedge>,
+    pub articulations: Vec<Articulation>,
+    // Grace notes take no time in the measure, cue notes take it but are not played
+    pub grace: Option<Grace>,
+    pub cue: bool,
+    pub tempo: Option<Tempo>,
+    pub lyrics: Vec<Lyric>,
+}
+
+impl Chord {
+    pub fn new(tones: Vec<Pitch>, len: i32) -> Self {
+        Self {
+            tones,
+            len,
+            dots: 0,
+            tuplet: None,
+            stem: None,
+            tied: vec![],
+            slurs: vec![],
+            dynamic: None,
+            wedges: vec![],
+            articulations: vec![],
+            grace: None,
+            cue: false,
+            tempo: None,
+            lyrics: vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Rest {
+    // Sounding length, after dots and tuplets
+    pub len: i32,
+    pub dots: u8,
+    pub tuplet: Option<Tuplet>,
+    // Fills the whole measure, whatever its length
+    pub measure: bool,
+    pub fermata: bool,
+}
+
+impl Rest {
+    pub fn new(len: i32) -> Self {
+        Self {
+            len,
+            dots: 0,
+            tuplet: None,
+            measure: false,
+            fermata: false,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Note {
-    Note(Vec<i8>, i32),
-    Rest(i32),
+    Chord(Chord),
+    Rest(Rest),
+    // Time passing in a voice with nothing written
+    Space(i32),
+}
+
+impl Note {
+    pub fn len(&self) -> i32 {
+        match self {
+            Note::Chord(chord) if chord.grace.is_some() => 0,
+            Note::Chord(chord) => chord.len,
+            Note::Rest(rest) => rest.len,
+            Note::Space(len) => *len,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Measure {
+    // Index of the first note of the measure
+    pub start: usize,
+    pub clef: Option<Clef>,
+    pub key: Option<Key>,
+    pub time: Option<TimeSignature>,
+    pub left: Barline,
+    pub right: Barline,
+    pub marks: Vec<Mark>,
+    // Number of the volta over the measure
+    pub ending: Option<u8>,
+    // Chord symbols by where in the measure they start
+    pub harmonies: Vec<(i32, Harmony)>,
+}
+
+impl Measure {
+    pub fn new(start: usize) -> Self {
+        Self {
+            start,
+            clef: None,
+            key: None,
+            left: Barline::Single,
+            right: Barline::Single,
+            marks: vec![],
+            ending: None,
+            time: None,
+            harmonies: vec![],
+        }
+    }
+}
+
+// Arc leaving a head whose other end is still to come
+#[derive(Debug)]
+struct Pending {
+    // Index into the stanza arcs, none once the system has ended
+    arc: Option<usize>,
+    note: usize,
+    tone: Pitch,
 }
 
 #[derive(Debug)]
 pub struct Collector {
-    quarters: Vec<f64>,
-    wholes: Vec<f64>,
+    pub staff: usize,
+    // Voices are numbered from 1, upper first
+    pub voice: u8,
+    heads: Vec<Pitch>,
+    // Stem shared by the pending heads, and the length their type, beams or flags give
+    stem: Option<Fixed>,
+    len: i32,
+    dots: u8,
+    tuplet: Option<Tuplet>,
+    direction: Option<Direction>,
+    slurs: Vec<Slur>,
+    dynamic: Option<Dynamic>,
+    wedges: Vec<Wedge>,
+    articulations: Vec<Articulation>,
+    grace: Option<Grace>,
+    cue: bool,
+    tempo: Option<Tempo>,
+    lyrics: Vec<Lyric>,
+    pending: Vec<Pending>,
+    // No chord written since the system broke, arcs left open may still end here
+    resumed: bool,
     x: Option<Fixed>,
+    pub clef: Clef,
+    pub key: Key,
+    pub time: Option<TimeSignature>,
+    // Accidentals in effect until the end of the measure, by step
+    alterations: HashMap<i32, i8>,
     pub notes: Vec<Note>,
+    pub measures: Vec<Measure>,
 }
 
 impl Collector {
-    pub fn new() -> Self {
+    pub fn new(staff: usize) -> Self {
         Collector {
-            quarters: vec![],
-            wholes: vec![],
+            staff,
+            voice: 1,
+            heads: vec![],
+            stem: None,
+            len: 480,
+            dots: 0,
+            tuplet: None,
+            direction: None,
+            slurs: vec![],
+            dynamic: None,
+            wedges: vec![],
+            articulations: vec![],
+            grace: None,
+            cue: false,
+            tempo: None,
+            lyrics: vec![],
+            pending: vec![],
+            resumed: false,
             x: None,
+            clef: Clef::treble(),
+            key: Key::default(),
+            time: None,
+            alterations: HashMap::new(),
             notes: vec![],
+            measures: vec![],
+        }
+    }
+
+    // Another voice on the same staff, silent in the measures gone by
+    pub fn follow(&self, voice: u8) -> Self {
+        let mut follower = Self::new(self.staff);
+        follower.voice = voice;
+        follower.clef = self.clef;
+        follower.key = self.key;
+        follower.time = self.time;
+        follower.alterations = self.alterations.clone();
+        for index in 0..self.measures.len() {
+            follower.measures.push(Measure::new(follower.notes.len()));
+            if index + 1 < self.measures.len() {
+                follower.notes.push(Note::Space(self.span(index)));
+            }
+        }
+        follower
+    }
+
+    pub fn measure(&self, index: usize) -> &[Note] {
+        let start = self.measures[index].start;
+        let end = self
+            .measures
+            .get(index + 1)
+            .map_or(self.notes.len(), |next| next.start);
+        &self.notes[start..end]
+    }
+
+    pub fn span(&self, index: usize) -> i32 {
+        self.measure(index).iter().map(Note::len).sum()
+    }
+
+    pub fn measure_len(&self) -> i32 {
+        match self.measures.len() {
+            0 => 0,
+            len => self.span(len - 1),
+        }
+    }
+
+    pub fn put_space(&mut self, len: i32) {
+        if len != 0 {
+            self.notes.push(Note::Space(len));
         }
     }
 
     pub fn prepare(&mut self) {
         self.clear();
         self.x = None;
+        self.alterations.clear();
+        self.measures.push(Measure::new(self.notes.len()));
     }
 
-    pub fn put_quarter(&mut self, x: Fixed, note: f64) {
-        self.quarters.push(note);
-        self.x = Some(x);
+    pub fn put_barlines(&mut self, left: Barline, right: Barline) {
+        if let Some(measure) = self.measures.last_mut() {
+            measure.left = left;
+            measure.right = right;
+        }
     }
 
-    pub fn put_whole(&mut self, x: Fixed, note: f64) {
-        self.wholes.push(note);
-        self.x = Some(x);
+    pub fn put_marks(&mut self, marks: &[Mark], ending: Option<u8>) {
+        if let Some(measure) = self.measures.last_mut() {
+            measure.marks = marks.to_vec();
+            measure.ending = ending;
+        }
     }
 
-    pub fn put_wing(&mut self, x: Fixed) {
-        let note = Note::Note(
-            self.quarters
-                .iter()
-                .map(|h| 0x3c + (*h * 4.) as i8)
-                .collect(),
-            240,
-        );
-        self.notes.push(note);
-        self.quarters.clear();
+    pub fn put_clef(&mut self, clef: Clef) {
+        self.clef = clef;
+        if let Some(measure) = self.measures.last_mut() {
+            measure.clef = Some(clef);
+        }
+    }
 
-        self.x = Some(x);
+    pub fn put_key(&mut self, key: Key) {
+        self.key = key;
+        if let Some(measure) = self.measures.last_mut() {
+            measure.key = Some(key);
+        }
+    }
+
+    pub fn put_time(&mut self, time: TimeSignature) {
+        self.time = Some(time);
+        if let Some(measure) = self.measures.last_mut() {
+            measure.time = Some(time);
+        }
+    }
+
+    // Total length of the current measure against the time signature, when they differ
+    pub fn check(&self) -> Option<(i32, i32)> {
+        let expected = self.time?.duration();
+        let found = self.measure_len();
+        if found != 0 && found != expected {
+            Some((found, expected))
+        } else {
+            None
+        }
+    }
+
+    pub fn put_accidental(&mut self, staff: &Staff, y: Fixed, accidental: Accidental) {
+        let pitch = self.clef.pitch(staff, y);
+        self.alterations.insert(pitch.step, accidental.alter());
+    }
+
+    pub fn pitch(&self, staff: &Staff, y: Fixed) -> Pitch {
+        let pitch = self.clef.pitch(staff, y);
+        match self.alterations.get(&pitch.step) {
+            Some(alter) => Pitch::new(pitch.step, *alter),
+            None => self.key.apply(pitch),
+        }
+    }
+
+    // Noteheads sharing a stem make up a chord, stemless ones when of the same length
+    // and aligned, give or take a head displaced by a second
+    pub fn put_head(
+        &mut self,
+        staff: &Staff,
+        point: &Point,
+        len: i32,
+        stem: Option<(Fixed, Direction)>,
+    ) {
+        let x = stem.map(|(x, _)| x);
+        let aligned = self
+            .x
+            .is_some_and(|last| (point.x - last).abs() <= staff.spacing * 2);
+        if self.stem != x
+            || self.heads.is_empty()
+            || (stem.is_none() && (self.len != len || !aligned))
+        {
+            self.clear();
+            self.len = len;
+            self.direction = stem.map(|(_, direction)| direction);
+        }
+        let pitch = self.pitch(staff, point.y);
+        self.heads.push(pitch);
+        self.stem = x;
+        self.x = Some(point.x);
+    }
+
+    pub fn put_dots(&mut self, dots: u8) {
+        if self.heads.is_empty() {
+            // Rests are written out right away
+            if let Some(Note::Rest(rest)) = self.notes.last_mut() {
+                rest.len = Self::dotted(rest.len, dots);
+                rest.dots = dots;
+            }
+        } else {
+            self.dots = self.dots.max(dots);
+        }
+    }
+
+    pub fn put_tuplet(&mut self, tuplet: Option<Tuplet>) {
+        if self.heads.is_empty() {
+            if let (Some(Note::Rest(rest)), Some(tuplet)) = (self.notes.last_mut(), tuplet) {
+                rest.len = tuplet.scale(rest.len);
+                rest.tuplet = Some(tuplet);
+            }
+        } else {
+            self.tuplet = tuplet;
+        }
+    }
+
+    pub fn put_dynamics(&mut self, dynamic: Option<Dynamic>, wedges: &[Wedge]) {
+        if !self.heads.is_empty() {
+            self.dynamic = self.dynamic.or(dynamic);
+            self.wedges.extend_from_slice(wedges);
+        }
     }
 
-    fn h2n(h: f64) -> i8 {
-        let i = (h * 2.) as i32;
-        let o = ((i / 7) * 12) as i8;
-        o + match (i % 7 + 7) % 7 {
-            0 => 0, // C
-            1 => 2,
-            2 => 4,
-            3 => 5,
-            4 => 7,
-            5 => 9,
-            6 => 11,
-            x => panic!("HEHE {}", x),
+    // Marks on any head of a chord belong to the whole chord; rests only hold fermatas
+    pub fn put_articulations(&mut self, articulations: &[Articulation]) {
+        if !self.heads.is_empty() {
+            for articulation in articulations {
+                if !self.articulations.contains(articulation) {
+                    self.articulations.push(*articulation);
+                }
+            }
+        } else if let Some(Note::Rest(rest)) = self.notes.last_mut() {
+            rest.fermata |= articulations.contains(&Articulation::Fermata);
         }
     }
 
-    fn clear(&mut self) {
-        if !self.quarters.is_empty() {
-            let note = Note::Note(
-                self.quarters.iter().map(|h| 0x3c + Self::h2n(*h)).collect(),
-                480,
-            );
-            self.notes.push(note);
-            self.quarters.clear();
+    pub fn put_small(&mut self, small: Option<Option<Grace>>) {
+        if let Some(grace) = small {
+            self.grace = grace;
+            self.cue = grace.is_none();
         }
-        if !self.wholes.is_empty() {
-            let note = Note::Note(
-                self.wholes.iter().map(|h| 0x3c + Self::h2n(*h)).collect(),
-                480 * 4,
-            );
-            self.notes.push(note);
-            self.wholes.clear();
+    }
+
+    pub fn put_tempo(&mut self, tempo: Option<Tempo>) {
+        if !self.heads.is_empty() && tempo.is_some() {
+            self.tempo = tempo;
+        }
+    }
+
+    pub fn put_lyrics(&mut self, lyrics: &[Lyric]) {
+        if !self.heads.is_empty() {
+            self.lyrics.extend_from_slice(lyrics);
+        }
+    }
+
+    // Start within the measure of the chord being read, or of the rest just written
+    pub fn onset(&self) -> i32 {
+        let written = match (self.heads.is_empty(), self.notes.last()) {
+            (true, Some(note)) => note.len(),
+            _ => 0,
+        };
+        self.measure_len() - written
+    }
+
+    pub fn put_harmony(&mut self, onset: i32, harmony: Harmony) {
+        if let Some(measure) = self.measures.last_mut() {
+            measure.harmonies.push((onset, harmony));
+            measure.harmonies.sort_by_key(|(onset, _)| *onset);
+        }
+    }
+
+    // Each dot adds half of what the previous one added
+    fn dotted(len: i32, dots: u8) -> i32 {
+        len * ((2 << dots) - 1) / (1 << dots)
+    }
+
+    // An arc between two heads of the same pitch is a tie, any other one a slur
+    pub fn put_arcs(&mut self, starts: &[usize], ends: &[usize]) {
+        let tone = match self.heads.last() {
+            Some(pitch) => *pitch,
+            None => return,
+        };
+        let note = self.notes.len();
+        for arc in ends {
+            let found = self
+                .pending
+                .iter()
+                .position(|pending| pending.arc == Some(*arc))
+                .or_else(|| {
+                    // Second half of an arc broken by the end of the system, on the first
+                    // chord of the next one: a tie if any of them has the pitch
+                    if !self.resumed {
+                        return None;
+                    }
+                    let broken = |pending: &Pending| pending.arc.is_none();
+                    self.pending
+                        .iter()
+                        .position(|pending| broken(pending) && pending.tone == tone)
+                        .or_else(|| self.pending.iter().position(broken))
+                });
+            if let Some(found) = found {
+                let pending = self.pending.remove(found);
+                if let Some(Note::Chord(chord)) = self.notes.get_mut(pending.note) {
+                    if pending.tone == tone {
+                        chord.tied.push(tone);
+                    } else {
+                        chord.slurs.push(Slur::Start);
+                        self.slurs.push(Slur::Stop);
+                    }
+                }
+            }
+        }
+        for arc in starts {
+            self.pending.push(Pending {
+                arc: Some(*arc),
+                note,
+                tone,
+            });
+        }
+    }
+
+    // Only arcs leaving the last chord of the system carry over, the rest never found
+    // their end
+    pub fn break_system(&mut self) {
+        let last = self
+            .notes
+            .iter()
+            .rposition(|note| matches!(note, Note::Chord(_)));
+        self.pending.retain(|pending| Some(pending.note) == last);
+        for pending in self.pending.iter_mut() {
+            pending.arc = None;
+        }
+        self.resumed = !self.pending.is_empty();
+    }
+
+    pub fn clear(&mut self) {
+        if !self.heads.is_empty() {
+            let len = Self::dotted(self.len, self.dots);
+            let len = self.tuplet.map_or(len, |tuplet| tuplet.scale(len));
+            let mut chord = Chord::new(std::mem::take(&mut self.heads), len);
+            chord.dots = self.dots;
+            chord.tuplet = self.tuplet;
+            chord.stem = self.direction;
+            chord.slurs = std::mem::take(&mut self.slurs);
+            chord.dynamic = self.dynamic.take();
+            chord.wedges = std::mem::take(&mut self.wedges);
+            chord.articulations = std::mem::take(&mut self.articulations);
+            chord.grace = self.grace.take();
+            chord.cue = std::mem::take(&mut self.cue);
+            chord.tempo = self.tempo.take();
+            chord.lyrics = std::mem::take(&mut self.lyrics);
+            self.notes.push(Note::Chord(chord));
+            if std::mem::take(&mut self.resumed) {
+                self.pending.retain(|pending| pending.arc.is_some());
+            }
+        }
+        self.stem = None;
+        self.grace = None;
+        self.cue = false;
+        self.dots = 0;
+        self.tuplet = None;
+        self.direction = None;
+    }
+
+    // Length of a full measure, common time until a time signature says otherwise
+    pub fn duration(&self) -> i32 {
+        self.time.map_or(1920, |time| time.duration())
+    }
+
+    pub fn put_measure_rest(&mut self) {
+        self.clear();
+        let mut rest = Rest::new(self.duration());
+        rest.measure = true;
+        self.notes.push(Note::Rest(rest));
+    }
+
+    // A whole rest alone in its measure lasts the whole measure, whatever the time
+    pub fn complete(&mut self) {
+        let duration = self.duration();
+        if let Some(measure) = self.measures.last() {
+            if let [Note::Rest(rest)] = &mut self.notes[measure.start..] {
+                if rest.len == 1920 && rest.dots == 0 && rest.tuplet.is_none() {
+                    rest.len = duration;
+                    rest.measure = true;
+                }
+            }
         }
     }
 
     pub fn put_rest(&mut self, x: Fixed, len: u8) {
         self.clear();
-        let note = Note::Rest(1920 / i32::from(len));
+        let note = Note::Rest(Rest::new(1920 / i32::from(len)));
         self.notes.push(note);
         self.x = Some(x);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn staff() -> Staff {
+        Staff::new(
+            Fixed::new(0),
+            Fixed::new(500),
+            Fixed::new(100),
+            5,
+            Fixed::new(10),
+        )
+    }
+
+    fn tones(collector: &Collector) -> Vec<Vec<Pitch>> {
+        collector
+            .notes
+            .iter()
+            .filter_map(|note| match note {
+                Note::Chord(chord) => Some(chord.tones.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn accidentals_last_until_the_barline() {
+        let staff = staff();
+        let mut collector = Collector::new(0);
+        collector.put_key(Key::new(-1));
+        collector.prepare();
+        // B4 on the middle line
+        collector.put_head(&staff, &Point::new(10, 120), 480, None);
+        collector.put_accidental(&staff, Fixed::new(120), Accidental::Natural);
+        collector.put_head(&staff, &Point::new(40, 120), 480, None);
+        // The same step an octave up keeps the key
+        collector.put_head(&staff, &Point::new(70, 85), 480, None);
+        collector.prepare();
+        collector.put_head(&staff, &Point::new(110, 120), 480, None);
+        collector.clear();
+        assert_eq!(
+            tones(&collector),
+            vec![
+                vec![Pitch::new(6, -1)],
+                vec![Pitch::new(6, 0)],
+                vec![Pitch::new(13, -1)],
+                vec![Pitch::new(6, -1)],
+            ]
+        );
+    }
+
+    #[test]
+    fn check_against_the_time_signature() {
+        let staff = staff();
+        let mut collector = Collector::new(0);
+        collector.prepare();
+        collector.put_time(TimeSignature::new(3, 4));
+        collector.put_head(&staff, &Point::new(10, 120), 960, None);
+        collector.put_head(&staff, &Point::new(60, 120), 480, None);
+        collector.clear();
+        assert_eq!(collector.check(), None);
+        collector.put_rest(Fixed::new(90), 4);
+        assert_eq!(collector.check(), Some((1920, 1440)));
+    }
+
+    #[test]
+    fn dotted_lengths() {
+        assert_eq!(Collector::dotted(960, 0), 960);
+        assert_eq!(Collector::dotted(960, 1), 1440);
+        assert_eq!(Collector::dotted(480, 2), 840);
+        assert_eq!(Collector::dotted(1920, 3), 3600);
+    }
+
+    #[test]
+    fn dots_on_chords_and_rests() {
+        let staff = staff();
+        let mut collector = Collector::new(0);
+        collector.prepare();
+        collector.put_head(&staff, &Point::new(10, 120), 960, None);
+        collector.put_dots(1);
+        collector.put_rest(Fixed::new(60), 8);
+        collector.put_dots(1);
+        collector.clear();
+        assert_eq!(collector.measure_len(), 1440 + 360);
+    }
+
+    #[test]
+    fn ties_and_slurs() {
+        let staff = staff();
+        let mut collector = Collector::new(0);
+        collector.prepare();
+        collector.put_head(&staff, &Point::new(10, 120), 480, None);
+        collector.put_arcs(&[0], &[]);
+        collector.put_head(&staff, &Point::new(60, 120), 480, None);
+        collector.put_arcs(&[1], &[0]);
+        collector.put_head(&staff, &Point::new(110, 115), 480, None);
+        collector.put_arcs(&[], &[1]);
+        collector.clear();
+        let chords = collector
+            .notes
+            .iter()
+            .filter_map(|note| match note {
+                Note::Chord(chord) => Some((chord.tied.clone(), chord.slurs.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chords,
+            vec![
+                (vec![Pitch::new(6, 0)], vec![]),
+                (vec![], vec![Slur::Start]),
+                (vec![], vec![Slur::Stop]),
+            ]
+        );
+    }
+
+    #[test]
+    fn ties_across_systems() {
+        let staff = staff();
+        let mut collector = Collector::new(0);
+        collector.prepare();
+        collector.put_head(&staff, &Point::new(10, 120), 480, None);
+        collector.put_arcs(&[3], &[]);
+        collector.clear();
+        collector.break_system();
+        collector.prepare();
+        // The arcs of the next system are numbered anew
+        collector.put_head(&staff, &Point::new(10, 120), 480, None);
+        collector.put_arcs(&[], &[0]);
+        collector.clear();
+        match &collector.notes[0] {
+            Note::Chord(chord) => assert_eq!(chord.tied, vec![Pitch::new(6, 0)]),
+            note => panic!("{:?}", note),
+        }
+    }
+
+    #[test]
+    fn graces_take_no_time_and_cues_do() {
+        let staff = staff();
+        let mut collector = Collector::new(0);
+        collector.put_head(&staff, &Point::new(10, 120), 240, None);
+        collector.put_small(Some(Some(Grace::Acciaccatura)));
+        collector.put_head(&staff, &Point::new(30, 120), 480, None);
+        collector.put_head(&staff, &Point::new(60, 120), 480, None);
+        collector.put_small(Some(None));
+        collector.clear();
+        let lens = collector.notes.iter().map(Note::len).collect::<Vec<_>>();
+        assert_eq!(lens, vec![0, 480, 480]);
+        match &collector.notes[2] {
+            Note::Chord(chord) => assert!(chord.cue && chord.grace.is_none()),
+            _ => panic!("not a chord"),
+        }
+    }
+
+    #[test]
+    fn only_arcs_off_the_last_chord_cross_systems() {
+        let staff = staff();
+        let mut collector = Collector::new(0);
+        collector.prepare();
+        // A slur whose end was never found, then a tie off the last chord
+        collector.put_head(&staff, &Point::new(10, 110), 480, None);
+        collector.put_arcs(&[1], &[]);
+        collector.clear();
+        collector.put_head(&staff, &Point::new(60, 120), 480, None);
+        collector.put_arcs(&[2], &[]);
+        collector.clear();
+        collector.break_system();
+        collector.prepare();
+        collector.put_head(&staff, &Point::new(10, 130), 480, None);
+        collector.put_arcs(&[], &[0]);
+        collector.clear();
+        // Arc ends past the first chord find nothing left open
+        collector.put_head(&staff, &Point::new(60, 120), 480, None);
+        collector.put_arcs(&[], &[1]);
+        collector.clear();
+        let arcs = collector
+            .notes
+            .iter()
+            .map(|note| match note {
+                Note::Chord(chord) => (chord.tied.len(), chord.slurs.clone()),
+                note => panic!("{:?}", note),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            arcs,
+            vec![
+                (0, vec![]),
+                (0, vec![Slur::Start]),
+                (0, vec![Slur::Stop]),
+                (0, vec![]),
+            ]
+        );
+    }
+}
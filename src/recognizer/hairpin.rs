@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+
+use crate::common::{Fixed, Line, Point};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wedge {
+    Crescendo,
+    Diminuendo,
+    Stop,
+}
+
+#[derive(Debug, Clone)]
+pub struct Hairpin {
+    pub x1: Fixed,
+    pub x2: Fixed,
+    // Centre line
+    pub y: Fixed,
+    pub crescendo: bool,
+}
+
+impl Hairpin {
+    // Ends of a flat line, left first
+    fn ends(line: &Line) -> Option<(Point, Point)> {
+        let (a, b) = (Point::new(line.x1, line.y1), Point::new(line.x2, line.y2));
+        let (left, right) = if a.x < b.x { (a, b) } else { (b, a) };
+        if (right.y - left.y).abs() * 4 > right.x - left.x {
+            None
+        } else {
+            Some((left, right))
+        }
+    }
+
+    // Pairs of flat lines meeting at one end and spreading apart at the other;
+    // returns the hairpins with the lines they are made of
+    pub fn detect(lines: &HashSet<Line>) -> (Vec<Hairpin>, Vec<Line>) {
+        let flat = lines
+            .iter()
+            .filter_map(|line| Self::ends(line).map(|ends| (line, ends)))
+            .collect::<Vec<_>>();
+        let mut used = HashSet::new();
+        let mut hairpins = vec![];
+        for (i, (a, (a1, a2))) in flat.iter().enumerate() {
+            for (b, (b1, b2)) in flat[i + 1..].iter() {
+                if used.contains(a) || used.contains(b) {
+                    continue;
+                }
+                let tolerance = (a2.x - a1.x) / 10;
+                let near = |p: &Point, q: &Point| {
+                    (p.x - q.x).abs() <= tolerance && (p.y - q.y).abs() <= tolerance
+                };
+                let aligned = |p: &Point, q: &Point| (p.x - q.x).abs() <= tolerance && !near(p, q);
+                let crescendo = if near(a1, b1) && aligned(a2, b2) {
+                    true
+                } else if near(a2, b2) && aligned(a1, b1) {
+                    false
+                } else {
+                    continue;
+                };
+                hairpins.push(Hairpin {
+                    x1: a1.x.min(b1.x),
+                    x2: a2.x.max(b2.x),
+                    y: (a1.y + a2.y + b1.y + b2.y) / 4,
+                    crescendo,
+                });
+                used.insert(*a);
+                used.insert(*b);
+            }
+        }
+        (hairpins, used.into_iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[Line]) -> HashSet<Line> {
+        lines.iter().cloned().collect()
+    }
+
+    #[test]
+    fn crescendo_opens_to_the_right() {
+        let (hairpins, used) = Hairpin::detect(&lines(&[
+            Line::new(100, 200, 300, 185),
+            Line::new(300, 215, 100, 200),
+        ]));
+        assert_eq!(hairpins.len(), 1);
+        assert_eq!(used.len(), 2);
+        let hairpin = &hairpins[0];
+        assert!(hairpin.crescendo);
+        assert_eq!((hairpin.x1, hairpin.x2), (100.into(), 300.into()));
+        assert_eq!(hairpin.y, 200.into());
+    }
+
+    #[test]
+    fn diminuendo_closes_to_the_right() {
+        let (hairpins, _) = Hairpin::detect(&lines(&[
+            Line::new(100, 185, 300, 200),
+            Line::new(100, 215, 300, 200),
+        ]));
+        assert_eq!(hairpins.len(), 1);
+        assert!(!hairpins[0].crescendo);
+    }
+
+    #[test]
+    fn parallel_and_steep_lines_are_not_hairpins() {
+        let (hairpins, used) = Hairpin::detect(&lines(&[
+            Line::new(100, 195, 300, 195),
+            Line::new(100, 205, 300, 205),
+            Line::new(400, 100, 420, 200),
+            Line::new(400, 100, 380, 200),
+        ]));
+        assert!(hairpins.is_empty());
+        assert!(used.is_empty());
+    }
+}
@@ -0,0 +1,419 @@
+use crate::common::{Point, Text};
+use crate::recognizer::{Pitch, Staff};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Suspended2,
+    Suspended4,
+    // Root and fifth only
+    Power,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Seventh {
+    Minor,
+    Major,
+    Diminished,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Harmony {
+    // Pitches within the octave from the middle C
+    pub root: Pitch,
+    pub quality: Quality,
+    pub seventh: Option<Seventh>,
+    pub sixth: bool,
+    // Added and altered degrees with their alteration, the fifth included
+    pub extensions: Vec<(u8, i8)>,
+    pub bass: Option<Pitch>,
+    // Symbol as printed, without root and bass
+    pub text: String,
+}
+
+impl Harmony {
+    // Note name with its accidental, and what follows it
+    fn note(text: &str) -> Option<(Pitch, &str)> {
+        let mut chars = text.chars();
+        let step = "CDEFGAB".find(chars.next()?)? as i32;
+        let rest = chars.as_str();
+        let (alter, rest) = match rest.chars().next() {
+            Some('#') => (1, &rest[1..]),
+            Some('b') => (-1, &rest[1..]),
+            _ => (0, rest),
+        };
+        Some((Pitch::new(step, alter), rest))
+    }
+
+    fn number(text: &str) -> Option<(u8, &str)> {
+        let digits = text.chars().take_while(char::is_ascii_digit).count();
+        let number = text[..digits].parse().ok()?;
+        Some((number, &text[digits..]))
+    }
+
+    // Extensions a number names: a ninth and up brings the seventh and the ninth along
+    fn extend(&mut self, number: u8, major: bool) -> Option<()> {
+        match number {
+            5 if self.quality == Quality::Major && self.seventh.is_none() => {
+                self.quality = Quality::Power
+            }
+            6 => self.sixth = true,
+            69 => {
+                self.sixth = true;
+                self.extensions.push((9, 0));
+            }
+            // Six-nine, no seventh
+            9 if self.sixth => self.extensions.push((9, 0)),
+            7 | 9 | 11 | 13 => {
+                self.seventh = Some(if major {
+                    Seventh::Major
+                } else if self.quality == Quality::Diminished && self.seventh.is_none() {
+                    Seventh::Diminished
+                } else {
+                    self.seventh.unwrap_or(Seventh::Minor)
+                });
+                if number >= 9 {
+                    self.extensions.push((9, 0));
+                }
+                if number >= 11 {
+                    self.extensions.push((number, 0));
+                }
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    pub fn parse(text: &str) -> Option<Harmony> {
+        let text = text.trim();
+        let (root, mut rest) = Self::note(text)?;
+        let mut bass = None;
+        if let Some(split) = rest.rfind('/') {
+            if let Some((pitch, "")) = Self::note(&rest[split + 1..]) {
+                bass = Some(pitch);
+                rest = &rest[..split];
+            }
+        }
+        let mut harmony = Harmony {
+            root,
+            quality: Quality::Major,
+            seventh: None,
+            sixth: false,
+            extensions: vec![],
+            bass,
+            text: rest.to_string(),
+        };
+        let mut major = false;
+        loop {
+            rest = rest.trim_start_matches(['(', ')', ',', '/', ' ']);
+            let next = rest.chars().next();
+            let altered =
+                rest[next.map_or(0, char::len_utf8)..].starts_with(|c: char| c.is_ascii_digit());
+            if rest.is_empty() {
+                break;
+            } else if let Some(after) = rest.strip_prefix('\u{394}') {
+                // The triangle alone stands for the major seventh
+                major = true;
+                rest = after;
+                if !after.starts_with(|c: char| c.is_ascii_digit()) {
+                    harmony.extend(7, true)?;
+                }
+            } else if let Some(after) = ["maj", "Maj", "ma", "M"]
+                .iter()
+                .find_map(|prefix| rest.strip_prefix(prefix))
+            {
+                major = true;
+                rest = after;
+            } else if let (Some(c @ ('#' | '+' | 'b')), true) = (next, altered) {
+                let (degree, after) = Self::number(&rest[1..])?;
+                if ![5, 9, 11, 13].contains(&degree) {
+                    return None;
+                }
+                let alter = if c == '#' || c == '+' { 1 } else { -1 };
+                harmony.extensions.push((degree, alter));
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("add") {
+                let (degree, after) = Self::number(after)?;
+                harmony.extensions.push((degree, 0));
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("sus") {
+                let (degree, after) = Self::number(after).unwrap_or((4, after));
+                harmony.quality = match degree {
+                    2 => Quality::Suspended2,
+                    4 => Quality::Suspended4,
+                    _ => return None,
+                };
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("alt") {
+                harmony.extend(7, false)?;
+                harmony.extensions.extend([(5, 1), (9, -1)]);
+                rest = after;
+            } else if let Some(after) = ["dim", "o", "\u{b0}"]
+                .iter()
+                .find_map(|prefix| rest.strip_prefix(prefix))
+            {
+                harmony.quality = Quality::Diminished;
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix('\u{f8}') {
+                // Half-diminished
+                harmony.quality = Quality::Diminished;
+                harmony.seventh = Some(Seventh::Minor);
+                rest = after;
+            } else if let Some(after) = ["aug", "+"]
+                .iter()
+                .find_map(|prefix| rest.strip_prefix(prefix))
+            {
+                harmony.quality = Quality::Augmented;
+                rest = after;
+            } else if let Some(after) = ["min", "mi", "m"]
+                .iter()
+                .find_map(|prefix| rest.strip_prefix(prefix))
+                .or_else(|| {
+                    // A dash is minor right after the root before a number or a major
+                    // seventh, "C-9" is minor with a ninth, flats are only ever "b"
+                    let after = rest.strip_prefix('-')?;
+                    let major = ["maj", "Maj", "ma", "M", "\u{394}"];
+                    if rest.len() == harmony.text.len()
+                        && (after.starts_with(|c: char| c.is_ascii_digit())
+                            || major.iter().any(|prefix| after.starts_with(prefix)))
+                    {
+                        Some(after)
+                    } else {
+                        None
+                    }
+                })
+            {
+                if harmony.quality != Quality::Major {
+                    return None;
+                }
+                harmony.quality = Quality::Minor;
+                rest = after;
+            } else {
+                let (number, after) = Self::number(rest)?;
+                harmony.extend(number, major)?;
+                rest = after;
+            }
+        }
+        // A minor seventh chord with a flat fifth is half-diminished
+        if let (Quality::Minor, Some(Seventh::Minor)) = (harmony.quality, harmony.seventh) {
+            if let Some(flat) = harmony.extensions.iter().position(|e| *e == (5, -1)) {
+                harmony.extensions.remove(flat);
+                harmony.quality = Quality::Diminished;
+            }
+        }
+        Some(harmony)
+    }
+
+    // Staff a chord symbol is written over
+    pub fn staff(staves: &[Staff], text: &Text) -> Option<usize> {
+        Self::parse(&text.text)?;
+        staves
+            .iter()
+            .enumerate()
+            .filter(|(_, staff)| {
+                staff.contains_x(text.point.x)
+                    && text.point.y < staff.y
+                    && staff.y - text.point.y <= staff.spacing * 6
+            })
+            .min_by_key(|(_, staff)| staff.y)
+            .map(|(index, _)| index)
+    }
+
+    pub fn find(staves: &[Staff], texts: &[Text]) -> Vec<(usize, Point, Harmony)> {
+        texts
+            .iter()
+            .filter_map(|text| {
+                let staff = Self::staff(staves, text)?;
+                Some((staff, text.point.clone(), Self::parse(&text.text)?))
+            })
+            .collect()
+    }
+
+    // MusicXML name of the chord, and whether it accounts for the seventh
+    pub fn kind(&self) -> (&'static str, bool) {
+        match (self.quality, self.seventh, self.sixth) {
+            (Quality::Major, None, true) => ("major-sixth", true),
+            (Quality::Minor, None, true) => ("minor-sixth", true),
+            (Quality::Major, Some(Seventh::Minor), _) => ("dominant", true),
+            (Quality::Major, Some(Seventh::Major), _) => ("major-seventh", true),
+            (Quality::Minor, Some(Seventh::Minor), _) => ("minor-seventh", true),
+            (Quality::Minor, Some(Seventh::Major), _) => ("major-minor", true),
+            (Quality::Diminished, Some(Seventh::Minor), _) => ("half-diminished", true),
+            (Quality::Diminished, Some(Seventh::Diminished), _) => ("diminished-seventh", true),
+            (Quality::Augmented, Some(Seventh::Minor), _) => ("augmented-seventh", true),
+            (quality, seventh, _) => {
+                let kind = match quality {
+                    Quality::Major => "major",
+                    Quality::Minor => "minor",
+                    Quality::Diminished => "diminished",
+                    Quality::Augmented => "augmented",
+                    Quality::Suspended2 => "suspended-second",
+                    Quality::Suspended4 => "suspended-fourth",
+                    Quality::Power => "power",
+                };
+                (kind, seventh.is_none())
+            }
+        }
+    }
+
+    // Semitones above the root of the tones making up the chord
+    pub fn intervals(&self) -> Vec<i8> {
+        let mut intervals = match self.quality {
+            Quality::Major => vec![0, 4, 7],
+            Quality::Minor => vec![0, 3, 7],
+            Quality::Diminished => vec![0, 3, 6],
+            Quality::Augmented => vec![0, 4, 8],
+            Quality::Suspended2 => vec![0, 2, 7],
+            Quality::Suspended4 => vec![0, 5, 7],
+            Quality::Power => vec![0, 7],
+        };
+        if self.sixth {
+            intervals.push(9);
+        }
+        match self.seventh {
+            Some(Seventh::Minor) => intervals.push(10),
+            Some(Seventh::Major) => intervals.push(11),
+            Some(Seventh::Diminished) => intervals.push(9),
+            None => {}
+        }
+        for &(degree, alter) in self.extensions.iter() {
+            let semitones = match degree {
+                5 => {
+                    intervals.retain(|interval| !(6..=8).contains(interval));
+                    7
+                }
+                9 => 14,
+                11 => 17,
+                13 => 21,
+                _ => continue,
+            };
+            intervals.push(semitones + alter);
+        }
+        intervals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> (Quality, Option<Seventh>, Vec<(u8, i8)>) {
+        let harmony = Harmony::parse(text).unwrap();
+        (harmony.quality, harmony.seventh, harmony.extensions)
+    }
+
+    #[test]
+    fn roots_and_basses() {
+        let harmony = Harmony::parse("Bb/F").unwrap();
+        assert_eq!(harmony.root, Pitch::new(6, -1));
+        assert_eq!(harmony.bass, Some(Pitch::new(3, 0)));
+        assert_eq!(harmony.text, "");
+        assert_eq!(Harmony::parse("F#m7").unwrap().root, Pitch::new(3, 1));
+        assert_eq!(Harmony::parse("H"), None);
+        assert_eq!(Harmony::parse("Allegro"), None);
+    }
+
+    #[test]
+    fn qualities() {
+        assert_eq!(parse("C"), (Quality::Major, None, vec![]));
+        assert_eq!(parse("Cm"), (Quality::Minor, None, vec![]));
+        assert_eq!(parse("Cmin"), (Quality::Minor, None, vec![]));
+        assert_eq!(parse("Cdim"), (Quality::Diminished, None, vec![]));
+        assert_eq!(parse("C+"), (Quality::Augmented, None, vec![]));
+        assert_eq!(parse("Csus"), (Quality::Suspended4, None, vec![]));
+        assert_eq!(parse("Csus2"), (Quality::Suspended2, None, vec![]));
+        assert_eq!(parse("C5"), (Quality::Power, None, vec![]));
+    }
+
+    #[test]
+    fn sevenths() {
+        assert_eq!(parse("G7"), (Quality::Major, Some(Seventh::Minor), vec![]));
+        assert_eq!(
+            parse("Cmaj7"),
+            (Quality::Major, Some(Seventh::Major), vec![])
+        );
+        assert_eq!(
+            parse("C\u{394}"),
+            (Quality::Major, Some(Seventh::Major), vec![])
+        );
+        assert_eq!(parse("Dm7"), (Quality::Minor, Some(Seventh::Minor), vec![]));
+        assert_eq!(
+            parse("Bdim7"),
+            (Quality::Diminished, Some(Seventh::Diminished), vec![])
+        );
+        assert_eq!(
+            parse("Bm7b5"),
+            (Quality::Diminished, Some(Seventh::Minor), vec![])
+        );
+        assert_eq!(
+            parse("B\u{f8}"),
+            (Quality::Diminished, Some(Seventh::Minor), vec![])
+        );
+    }
+
+    #[test]
+    fn extensions_and_alterations() {
+        assert_eq!(
+            parse("G9"),
+            (Quality::Major, Some(Seventh::Minor), vec![(9, 0)])
+        );
+        assert_eq!(
+            parse("G7(b9)"),
+            (Quality::Major, Some(Seventh::Minor), vec![(9, -1)])
+        );
+        assert_eq!(
+            parse("C7b9"),
+            (Quality::Major, Some(Seventh::Minor), vec![(9, -1)])
+        );
+        assert_eq!(
+            parse("G7#11"),
+            (Quality::Major, Some(Seventh::Minor), vec![(11, 1)])
+        );
+        assert_eq!(parse("Cadd9"), (Quality::Major, None, vec![(9, 0)]));
+        assert!(Harmony::parse("C6/9").unwrap().sixth);
+        assert_eq!(Harmony::parse("C69").unwrap().extensions, vec![(9, 0)]);
+    }
+
+    #[test]
+    fn dash_is_minor_only_where_it_cannot_be_a_hyphen() {
+        assert_eq!(parse("A-7"), (Quality::Minor, Some(Seventh::Minor), vec![]));
+        assert_eq!(parse("A-6").0, Quality::Minor);
+        assert_eq!(
+            parse("A-maj7"),
+            (Quality::Minor, Some(Seventh::Major), vec![])
+        );
+        assert_eq!(
+            parse("C-9"),
+            (Quality::Minor, Some(Seventh::Minor), vec![(9, 0)])
+        );
+        assert_eq!(
+            parse("C-11"),
+            (Quality::Minor, Some(Seventh::Minor), vec![(9, 0), (11, 0)])
+        );
+        assert_eq!(Harmony::parse("A-"), None);
+        assert_eq!(Harmony::parse("Be-"), None);
+        assert_eq!(Harmony::parse("A7-"), None);
+        // A flat is written "b", a dash further on is no alteration
+        assert_eq!(Harmony::parse("C7-9"), None);
+    }
+
+    #[test]
+    fn chord_tones() {
+        assert_eq!(Harmony::parse("C").unwrap().intervals(), vec![0, 4, 7]);
+        assert_eq!(
+            Harmony::parse("G7b9").unwrap().intervals(),
+            vec![0, 4, 7, 10, 13]
+        );
+        assert_eq!(
+            Harmony::parse("C7#5").unwrap().intervals(),
+            vec![0, 4, 10, 8]
+        );
+        assert_eq!(
+            Harmony::parse("Cm7").unwrap().kind(),
+            ("minor-seventh", true)
+        );
+    }
+}
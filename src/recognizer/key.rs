@@ -0,0 +1,228 @@
+use crate::common::{Accidental, Object, Type};
+use crate::recognizer::{Clef, Pitch, Staff};
+
+// Degrees in the order they enter a key signature
+const SHARPS: [usize; 7] = [3, 0, 4, 1, 5, 2, 6];
+const FLATS: [usize; 7] = [6, 2, 5, 1, 4, 0, 3];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Key {
+    // Positive for sharps, negative for flats
+    pub fifths: i8,
+}
+
+impl Key {
+    pub fn new(fifths: i8) -> Self {
+        Self { fifths }
+    }
+
+    pub fn alter(&self, degree: usize) -> i8 {
+        let count = self.fifths.unsigned_abs() as usize;
+        if self.fifths > 0 && SHARPS[..count].contains(&degree) {
+            1
+        } else if self.fifths < 0 && FLATS[..count].contains(&degree) {
+            -1
+        } else {
+            0
+        }
+    }
+
+    pub fn apply(&self, pitch: Pitch) -> Pitch {
+        Pitch::new(pitch.step, self.alter(pitch.degree()))
+    }
+
+    fn spell(accidentals: &[(Accidental, Pitch)]) -> Option<Key> {
+        // Naturals in front cancel the previous key
+        let signs = accidentals
+            .iter()
+            .skip_while(|(accidental, _)| *accidental == Accidental::Natural)
+            .collect::<Vec<_>>();
+        let (order, sign) = match signs.first() {
+            None => return Some(Key::new(0)),
+            Some((Accidental::Sharp, _)) => (SHARPS, 1),
+            Some((Accidental::Flat, _)) => (FLATS, -1),
+            Some(_) => return None,
+        };
+        if signs.len() > order.len() {
+            return None;
+        }
+        for (i, (accidental, pitch)) in signs.iter().enumerate() {
+            if *accidental != signs[0].0 || pitch.degree() != order[i] {
+                return None;
+            }
+        }
+        Some(Key::new(sign * signs.len() as i8))
+    }
+
+    // A key signature is the run of accidentals after the clef, at the start of a system
+    // or after a double barline, returned with the indices of the objects it is made of;
+    // accidentals just before a head on their line belong to the note and end the run
+    pub fn find(
+        objects: &[(usize, &Object)],
+        mut clef: Clef,
+        staff: &Staff,
+        opening: bool,
+    ) -> Option<(Key, Vec<usize>)> {
+        let mut accidentals = vec![];
+        let mut indices = vec![];
+        let mut after_clef = false;
+        let mut end = objects.len();
+        for (i, (index, obj)) in objects.iter().enumerate() {
+            match obj.t {
+                Type::Clef(sign, octave) if accidentals.is_empty() => {
+                    clef = Clef::locate(sign, octave, staff, obj.point.y);
+                    after_clef = true;
+                }
+                Type::Accidental(accidental) => {
+                    accidentals.push((accidental, obj));
+                    indices.push(*index);
+                }
+                _ => {
+                    end = i;
+                    break;
+                }
+            }
+        }
+        if !opening && !after_clef {
+            return None;
+        }
+        let heads = objects[end..]
+            .iter()
+            .filter(|(_, obj)| matches!(obj.t, Type::Head(_)))
+            .collect::<Vec<_>>();
+        let own = accidentals.iter().position(|(_, accidental)| {
+            let pitch = clef.pitch(staff, accidental.point.y);
+            heads.iter().any(|(_, head)| {
+                head.point.x - accidental.point.x < staff.spacing * 2
+                    && clef.pitch(staff, head.point.y) == pitch
+            })
+        });
+        if let Some(own) = own {
+            accidentals.truncate(own);
+            indices.truncate(own);
+        }
+        if accidentals.is_empty() {
+            return None;
+        }
+        let accidentals = accidentals
+            .iter()
+            .map(|(accidental, obj)| (*accidental, clef.pitch(staff, obj.point.y)))
+            .collect::<Vec<_>>();
+        Key::spell(&accidentals).map(|key| (key, indices))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Fixed, Point, Sign};
+
+    fn staff() -> Staff {
+        Staff::new(
+            Fixed::new(0),
+            Fixed::new(500),
+            Fixed::new(100),
+            5,
+            Fixed::new(10),
+        )
+    }
+
+    fn object(t: Type, x: i64, y: i64) -> Object {
+        Object::new(t, Point::new(x, y))
+    }
+
+    fn sharp(x: i64, y: i64) -> Object {
+        object(Type::Accidental(Accidental::Sharp), x, y)
+    }
+
+    fn flat(x: i64, y: i64) -> Object {
+        object(Type::Accidental(Accidental::Flat), x, y)
+    }
+
+    fn natural(x: i64, y: i64) -> Object {
+        object(Type::Accidental(Accidental::Natural), x, y)
+    }
+
+    fn find(objects: &[Object], opening: bool) -> Option<(Key, Vec<usize>)> {
+        let objects = objects.iter().enumerate().collect::<Vec<_>>();
+        Key::find(&objects, Clef::treble(), &staff(), opening)
+    }
+
+    fn degrees(accidental: Accidental, steps: &[i32]) -> Vec<(Accidental, Pitch)> {
+        steps
+            .iter()
+            .map(|step| (accidental, Pitch::new(*step, 0)))
+            .collect()
+    }
+
+    #[test]
+    fn spell_in_order() {
+        // F C G
+        let sharps = degrees(Accidental::Sharp, &[10, 7, 11]);
+        assert_eq!(Key::spell(&sharps), Some(Key::new(3)));
+        // B E A D
+        let flats = degrees(Accidental::Flat, &[6, 9, 5, 8]);
+        assert_eq!(Key::spell(&flats), Some(Key::new(-4)));
+    }
+
+    #[test]
+    fn spell_cancelled() {
+        let naturals = degrees(Accidental::Natural, &[6, 9]);
+        assert_eq!(Key::spell(&naturals), Some(Key::new(0)));
+        let mut change = naturals;
+        change.extend(degrees(Accidental::Sharp, &[10]));
+        assert_eq!(Key::spell(&change), Some(Key::new(1)));
+    }
+
+    #[test]
+    fn spell_rejects_other_runs() {
+        assert_eq!(Key::spell(&degrees(Accidental::Sharp, &[7, 10])), None);
+        let mut mixed = degrees(Accidental::Sharp, &[10]);
+        mixed.extend(degrees(Accidental::Flat, &[9]));
+        assert_eq!(Key::spell(&mixed), None);
+    }
+
+    #[test]
+    fn apply_to_pitch() {
+        let key = Key::new(-3);
+        assert_eq!(key.apply(Pitch::new(-1, 0)), Pitch::new(-1, -1));
+        assert_eq!(key.apply(Pitch::new(3, 0)), Pitch::new(3, 0));
+    }
+
+    #[test]
+    fn find_after_clef() {
+        let objects = [
+            object(Type::Clef(Sign::G, 0), 5, 130),
+            sharp(20, 100),
+            sharp(26, 115),
+            object(Type::Head(4), 60, 120),
+        ];
+        assert_eq!(find(&objects, false), Some((Key::new(2), vec![1, 2])));
+    }
+
+    #[test]
+    fn find_only_at_openings() {
+        let objects = [flat(5, 120), flat(11, 105), object(Type::Head(4), 60, 130)];
+        assert_eq!(find(&objects, true), Some((Key::new(-2), vec![0, 1])));
+        assert_eq!(find(&objects, false), None);
+    }
+
+    #[test]
+    fn find_leaves_note_accidentals() {
+        // F sharp in front of a D and F chord, the lower head first
+        let objects = [
+            sharp(5, 100),
+            object(Type::Head(4), 15, 110),
+            object(Type::Head(4), 15, 100),
+        ];
+        assert_eq!(find(&objects, true), None);
+        // Natural on the first note after a signature of two flats
+        let objects = [
+            flat(5, 120),
+            flat(11, 105),
+            natural(40, 125),
+            object(Type::Head(4), 50, 125),
+        ];
+        assert_eq!(find(&objects, true), Some((Key::new(-2), vec![0, 1])));
+    }
+}
@@ -0,0 +1,285 @@
+use crate::common::{Dynamic, Fixed, HorzLine, Point, Text};
+use crate::recognizer::{Harmony, Staff};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lyric {
+    // Verses are numbered from 1, top line first
+    pub verse: u8,
+    pub text: String,
+    // The word goes on into the next syllable
+    pub hyphen: bool,
+    // The syllable is held over the notes after it
+    pub extend: bool,
+}
+
+impl Lyric {
+    // Performance directions set in the same type as lyrics
+    fn direction(words: &str) -> bool {
+        let lower = words.to_lowercase();
+        let first = lower.split_whitespace().next().unwrap_or("");
+        [
+            "cresc.", "decresc.", "dim.", "espr.", "sim.", "ped.", "ped", "*",
+        ]
+        .contains(&first)
+            || [
+                "crescendo",
+                "decrescendo",
+                "diminuendo",
+                "dolce",
+                "espressivo",
+                "cantabile",
+                "legato",
+                "leggiero",
+                "marcato",
+                "sempre",
+                "simile",
+                "subito",
+                "tranquillo",
+            ]
+            .contains(&first.trim_end_matches(|c: char| !c.is_alphabetic()))
+    }
+
+    // Staff right above a text that may be a lyric syllable
+    fn staff(staves: &[Staff], text: &Text) -> Option<usize> {
+        let words = text.text.trim();
+        let numbered = words.ends_with('.')
+            && words.len() > 1
+            && words[..words.len() - 1].chars().all(|c| c.is_ascii_digit());
+        // Fingerings, possibly a change of finger as "3-1"
+        let fingering = words.chars().any(|c| c.is_ascii_digit())
+            && words.chars().all(|c| c.is_ascii_digit() || c == '-');
+        if words.is_empty()
+            || numbered
+            || fingering
+            || Dynamic::parse(words).is_some()
+            || Self::direction(words)
+        {
+            return None;
+        }
+        Self::under(staves, text.point.y, text.point.x)
+    }
+
+    fn under(staves: &[Staff], y: Fixed, x: Fixed) -> Option<usize> {
+        staves
+            .iter()
+            .enumerate()
+            .filter(|(_, staff)| {
+                staff.contains_x(x)
+                    && staff.bottom() + staff.spacing * 2 < y
+                    && y <= staff.bottom() + staff.spacing * 16
+            })
+            .max_by_key(|(_, staff)| staff.y)
+            .map(|(index, _)| index)
+    }
+
+    // A syllable starts a little left of the note it is sung on, the longer the further
+    fn sung(staves: &[Staff], staff: usize, text: &Text, heads: &[&Point]) -> bool {
+        let spacing = staves[staff].spacing;
+        let len = text.text.trim().chars().count() as i64;
+        heads.iter().any(|head| {
+            Staff::nearest(staves, head.y, head.y) == staff
+                && text.point.x - spacing <= head.x
+                && head.x <= text.point.x + spacing * (len + 2) / 2
+        })
+    }
+
+    // Syllables of a system by staff and where they stand; hyphens and extenders set
+    // apart, as "-", "_" or a line along the baseline, go to the syllable before them.
+    // A verse is a baseline under a staff holding several syllables sung on its notes,
+    // not all of them chord symbols
+    pub fn find(
+        texts: &[&Text],
+        staves: &[Staff],
+        extenders: &[HorzLine],
+        heads: &[&Point],
+    ) -> Vec<(usize, Point, Lyric)> {
+        let mut placed = texts
+            .iter()
+            .filter_map(|text| Self::staff(staves, text).map(|staff| (staff, *text)))
+            .collect::<Vec<_>>();
+        placed.sort_by_key(|(_, text)| text.point.x);
+        let joining = |words: &str| words == "-" || words.chars().all(|c| c == '_');
+        // Baselines under each staff with how many syllables and chord symbols they hold
+        let mut lines: Vec<Vec<(Fixed, usize, usize)>> = vec![vec![]; staves.len()];
+        for (staff, text) in placed.iter() {
+            let words = text.text.trim();
+            if joining(words) || !Self::sung(staves, *staff, text, heads) {
+                continue;
+            }
+            let spacing = staves[*staff].spacing;
+            let chord = Harmony::parse(words).is_some() as usize;
+            let verses = &mut lines[*staff];
+            match verses
+                .iter_mut()
+                .find(|(y, _, _)| (*y - text.point.y).abs() <= spacing)
+            {
+                Some((_, count, chords)) => {
+                    *count += 1;
+                    *chords += chord;
+                }
+                None => verses.push((text.point.y, 1, chord)),
+            }
+        }
+        for verses in lines.iter_mut() {
+            verses.retain(|(_, count, chords)| *count > 1 && chords < count);
+            verses.sort();
+        }
+        let verse = |staff: usize, y: Fixed| {
+            lines[staff]
+                .iter()
+                .position(|(line, _, _)| (*line - y).abs() <= staves[staff].spacing)
+                .map(|index| index as u8 + 1)
+        };
+        let mut syllables: Vec<(usize, Point, Lyric)> = vec![];
+        for (staff, text) in placed {
+            let verse = match verse(staff, text.point.y) {
+                Some(verse) => verse,
+                None => continue,
+            };
+            let words = text.text.trim();
+            let extender = words.chars().all(|c| c == '_');
+            let last = syllables
+                .iter_mut()
+                .rev()
+                .find(|(s, _, lyric)| *s == staff && lyric.verse == verse);
+            match last {
+                Some((_, _, lyric)) if words == "-" => lyric.hyphen = true,
+                Some((_, _, lyric)) if extender => lyric.extend = true,
+                // Carried over from the system before
+                _ if joining(words) => {}
+                _ if !Self::sung(staves, staff, text, heads) => {}
+                _ => syllables.push((
+                    staff,
+                    text.point.clone(),
+                    Lyric {
+                        verse,
+                        text: words.trim_end_matches('-').to_string(),
+                        hyphen: words.ends_with('-'),
+                        extend: false,
+                    },
+                )),
+            }
+        }
+        for line in extenders {
+            let found = syllables
+                .iter_mut()
+                .filter(|(staff, point, _)| {
+                    (point.y - line.y).abs() <= staves[*staff].spacing && point.x < line.x1
+                })
+                .max_by_key(|(_, point, _)| point.x);
+            if let Some((_, _, lyric)) = found {
+                lyric.extend = true;
+            }
+        }
+        syllables
+    }
+
+    pub fn is_extender(staves: &[Staff], line: &HorzLine) -> bool {
+        Self::under(staves, line.y, line.x1).is_some()
+            && line.thickness <= staves[0].spacing / 4
+            && line.len() >= staves[0].spacing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A piano system: staves at 100 and 250, a space of 10 apart
+    fn staves() -> Vec<Staff> {
+        [100, 250]
+            .iter()
+            .map(|y| {
+                Staff::new(
+                    Fixed::new(0),
+                    Fixed::new(500),
+                    Fixed::new(*y),
+                    5,
+                    Fixed::new(10),
+                )
+            })
+            .collect()
+    }
+
+    fn find(texts: &[(&str, i64, i64)], heads: &[(i64, i64)]) -> Vec<(usize, Lyric)> {
+        let texts = texts
+            .iter()
+            .map(|(text, x, y)| Text::new(text.to_string(), Point::new(*x, *y)))
+            .collect::<Vec<_>>();
+        let heads = heads
+            .iter()
+            .map(|(x, y)| Point::new(*x, *y))
+            .collect::<Vec<_>>();
+        Lyric::find(
+            &texts.iter().collect::<Vec<_>>(),
+            &staves(),
+            &[],
+            &heads.iter().collect::<Vec<_>>(),
+        )
+        .into_iter()
+        .map(|(staff, _, lyric)| (staff, lyric))
+        .collect()
+    }
+
+    fn lyric(verse: u8, text: &str, hyphen: bool) -> Lyric {
+        Lyric {
+            verse,
+            text: text.to_string(),
+            hyphen,
+            extend: false,
+        }
+    }
+
+    #[test]
+    fn syllables_between_piano_staves_go_up() {
+        let heads = [(100, 120), (200, 120), (300, 120), (100, 270)];
+        let texts = [
+            ("Hel-", 95, 180),
+            ("lo", 195, 180),
+            // Spelled like a chord, but on the verse line
+            ("Am", 297, 181),
+            ("cresc.", 150, 190),
+            ("3", 300, 195),
+            // Under no note
+            ("la", 420, 180),
+            // Alone on its line
+            ("solo", 97, 330),
+        ];
+        assert_eq!(
+            find(&texts, &heads),
+            vec![
+                (0, lyric(1, "Hel", true)),
+                (0, lyric(1, "lo", false)),
+                (0, lyric(1, "Am", false)),
+            ]
+        );
+    }
+
+    #[test]
+    fn verses_numbered_down() {
+        let heads = [(100, 120), (200, 120)];
+        let texts = [
+            ("one", 95, 180),
+            ("two", 195, 180),
+            ("-", 150, 180),
+            ("three", 95, 200),
+            ("four", 195, 200),
+        ];
+        assert_eq!(
+            find(&texts, &heads),
+            vec![
+                (0, lyric(1, "one", true)),
+                (0, lyric(2, "three", false)),
+                (0, lyric(1, "two", false)),
+                (0, lyric(2, "four", false)),
+            ]
+        );
+    }
+
+    #[test]
+    fn chord_symbols_make_no_verse() {
+        let heads = [(100, 270), (200, 270)];
+        let texts = [("C", 98, 180), ("G7", 198, 180), ("Ped.", 100, 330)];
+        assert!(find(&texts, &heads).is_empty());
+    }
+}
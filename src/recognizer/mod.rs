@@ -1,89 +1,150 @@
+mod arc;
 mod bar;
+mod beam;
+mod clef;
 mod collector;
+mod hairpin;
+mod harmony;
+mod key;
+mod lyric;
+mod pitch;
+mod repeat;
+mod staff;
 mod stanza;
+mod tempo;
+mod time;
+mod tuplet;
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 
-use crate::common::Fixed;
+use failure::Fail;
+
+use crate::common::{Fixed, HorzLine, Quadrangle, Type};
 use crate::Parser;
-pub use bar::Bar;
+pub use arc::Arc;
+pub use bar::{Bar, Barline};
+pub use beam::Beam;
+pub use clef::Clef;
+pub use hairpin::{Hairpin, Wedge};
+pub use harmony::{Harmony, Seventh};
+pub use key::Key;
+pub use lyric::Lyric;
+pub use pitch::Pitch;
+pub use repeat::{unfold, Mark};
+pub use staff::Staff;
 pub use stanza::Stanza;
+pub use tempo::{Change, Tempo};
+pub use time::TimeSignature;
+pub use tuplet::Tuplet;
 
-pub use collector::{Collector, Note};
+pub use collector::{Chord, Collector, Grace, Measure, Note, Rest, Slur};
 
-#[derive(Debug, Fail)]
+#[derive(Debug)]
 pub enum Error {
-    #[fail(display = "no stanza")]
     NoStanza,
-    #[fail(display = "empty chunk")]
-    EmptyChunk,
+    Duration {
+        x: Fixed,
+        y: Fixed,
+        found: i32,
+        expected: i32,
+    },
+    Stemless {
+        x: Fixed,
+        y: Fixed,
+    },
+    Leftover {
+        count: usize,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoStanza => write!(f, "no stanza"),
+            Error::Duration {
+                x,
+                y,
+                found,
+                expected,
+            } => write!(
+                f,
+                "bar at ({:?}, {:?}) lasts {} ticks instead of {}",
+                x, y, found, expected
+            ),
+            Error::Stemless { x, y } => {
+                write!(f, "no stem found for the head at ({:?}, {:?})", x, y)
+            }
+            Error::Leftover { count } => write!(f, "{} lines left unrecognized", count),
+        }
+    }
 }
 
+impl Fail for Error {}
+
 #[derive(Debug)]
 pub struct Recognizer {
     parser: Parser,
+    pub warnings: Vec<Error>,
 }
 
 impl Recognizer {
     pub fn new(parser: Parser) -> Self {
-        Self { parser }
+        Self {
+            parser,
+            warnings: vec![],
+        }
     }
 
-    fn detect_width(&self) -> Result<Fixed, failure::Error> {
-        let mut counts = BTreeMap::new();
-        for line in self.parser.horz_lines.iter() {
-            let len = line.len();
-            counts.insert(len, *counts.get(&len).unwrap_or(&0) + 1);
+    fn merge_horz_lines(&mut self) {
+        let mut lines = self.parser.horz_lines.drain().collect::<Vec<_>>();
+        lines.sort_by_key(|line| (line.y, line.thickness, line.x1));
+        let mut merged: Vec<HorzLine> = vec![];
+        for line in lines {
+            if !merged.last_mut().is_some_and(|last| last.merge(&line)) {
+                merged.push(line);
+            }
         }
-        Ok(*counts
-            .iter()
-            .rfind(|&(_len, count)| count % 5 == 0)
-            .ok_or(Error::NoStanza)?
-            .0)
+        self.parser.horz_lines = merged.into_iter().collect();
     }
 
     fn detect_stanzas(&mut self) -> Result<Vec<Stanza>, failure::Error> {
-        let width = self.detect_width()?;
-        let mut x = None;
-        let mut ys = BTreeSet::new();
-        self.parser.horz_lines.retain(|line| {
-            if line.len() == width {
-                x = Some(line.x1);
-                ys.insert(line.y);
-                true
-            } else {
-                true
-            }
-        });
-        let x = x.ok_or(Error::NoStanza)?;
-        Ok(ys
+        let staves = Staff::detect(&self.parser.horz_lines, &self.parser.vert_lines);
+        if staves.is_empty() {
+            return Err(failure::Error::from(Error::NoStanza));
+        }
+        self.parser
+            .horz_lines
+            .retain(|line| !staves.iter().any(|staff| staff.owns(line)));
+        let joiners = self
+            .parser
+            .vert_lines
             .iter()
-            .collect::<Vec<_>>()
-            .chunks(10)
-            .map(|chunk| -> Result<_, failure::Error> {
-                let &&first = chunk.first().ok_or(Error::EmptyChunk)?;
-                let &&last = chunk.last().ok_or(Error::EmptyChunk)?;
-                let high = chunk[0..5]
-                    .windows(2)
-                    .map(|w| *w[1] - *w[0])
-                    .collect::<BTreeSet<_>>();
-                let low = chunk[0..5]
-                    .windows(2)
-                    .map(|w| *w[1] - *w[0])
-                    .collect::<BTreeSet<_>>();
-                assert!(high.len() == 1 && low.len() == 1);
-                Ok(Stanza::new(
-                    x,
-                    first,
-                    width,
-                    last - first,
-                    *high.iter().next().unwrap(),
-                ))
-            })
-            .collect::<Result<Vec<_>, _>>()?)
+            .cloned()
+            .chain(
+                self.parser
+                    .quadras
+                    .iter()
+                    .filter_map(Quadrangle::as_vert_line),
+            )
+            .collect::<Vec<_>>();
+        let mut systems: Vec<Vec<Staff>> = vec![];
+        for staff in staves {
+            match systems.last_mut() {
+                Some(system)
+                    if joiners
+                        .iter()
+                        .any(|line| system.last().unwrap().is_joined(&staff, line)) =>
+                {
+                    system.push(staff)
+                }
+                _ => systems.push(vec![staff]),
+            }
+        }
+        Ok(systems.into_iter().map(Stanza::new).collect())
     }
 
-    fn debug_vert_lines(&self) {
+    // Draws what no recognizer took and returns how much of it there is
+    fn debug_vert_lines(&self) -> usize {
         let mut test = crate::svg::Svg::new();
         let mut count = 0;
         for line in self.parser.vert_lines.iter() {
@@ -106,26 +167,116 @@ impl Recognizer {
             test.circle(&obj.point)
         }
         test.save("target/output.svg").unwrap();
-        assert!(count == 0);
+        count
     }
 
-    pub fn process(&mut self) -> Result<(), failure::Error> {
+    pub fn process(&mut self) -> Result<Vec<Collector>, failure::Error> {
+        self.merge_horz_lines();
         let mut stanzas = self.detect_stanzas()?;
         for stanza in stanzas.iter_mut() {
             self.parser
                 .vert_lines
-                .retain(|line| !stanza.insert_bar(line));
+                .retain(|line| !stanza.insert_bar(line, Fixed::new(0)));
+            self.parser.quadras.retain(|quadra| {
+                !quadra
+                    .as_vert_line()
+                    .is_some_and(|line| stanza.insert_bar(&line, quadra.width()))
+            });
+            let dashes = stanza.insert_dashes(&self.parser.vert_lines);
+            self.parser.vert_lines.retain(|line| !dashes.contains(line));
             stanza.sort_bars();
         }
+        let (mut hairpins, used) = Hairpin::detect(&self.parser.lines);
+        self.parser.lines.retain(|line| !used.contains(line));
+        let dots = self
+            .parser
+            .objects
+            .iter()
+            .filter(|obj| matches!(obj.t, Type::Dot))
+            .cloned()
+            .collect::<Vec<_>>();
         for stanza in stanzas.iter_mut().rev() {
+            hairpins.retain(|hairpin| !stanza.put_hairpin(hairpin));
+            self.parser.lines.retain(|line| !stanza.put_slash(line));
+            self.parser
+                .objects
+                .retain(|obj| !stanza.put_repeat_dot(obj, &dots));
             self.parser.objects.retain(|obj| !stanza.put_object(obj));
+            self.parser.texts.retain(|text| !stanza.put_text(text));
             self.parser.vert_lines.retain(|line| !stanza.put_stem(line));
+            self.parser
+                .quadras
+                .retain(|quadra| !stanza.put_block(quadra));
+            self.parser
+                .quadras
+                .retain(|quadra| !stanza.put_beam(quadra));
+            self.parser.curves.retain(|curve| !stanza.put_arc(curve));
+            self.parser
+                .horz_lines
+                .retain(|line| !stanza.put_bracket(line));
+            self.parser
+                .horz_lines
+                .retain(|line| !stanza.put_ledger(line));
+            self.parser
+                .horz_lines
+                .retain(|line| !stanza.put_volta(line));
+            self.parser
+                .horz_lines
+                .retain(|line| !stanza.put_extender(line));
         }
+        let channels = stanzas.iter().map(|stanza| stanza.staves.len()).max();
+        let mut collectors = (0..channels.unwrap_or(0))
+            .map(Collector::new)
+            .collect::<Vec<_>>();
         for stanza in stanzas.iter_mut() {
-            stanza.process();
+            self.warnings.extend(stanza.process(&mut collectors));
         }
-        self.debug_vert_lines();
+        let count = self.debug_vert_lines();
+        if count > 0 {
+            self.warnings.push(Error::Leftover { count });
+        }
+
+        Ok(collectors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recognizer(lines: &[HorzLine]) -> Recognizer {
+        Recognizer::new(Parser {
+            horz_lines: lines.iter().cloned().collect(),
+            vert_lines: Default::default(),
+            quadras: Default::default(),
+            lines: Default::default(),
+            curves: Default::default(),
+            objects: vec![],
+            texts: vec![],
+        })
+    }
 
-        Ok(())
+    #[test]
+    fn merge_segmented_lines() {
+        let mut recognizer = recognizer(&[
+            // One staff line drawn in three pieces, the last a little apart
+            HorzLine::new(0, 100, 50, 1),
+            HorzLine::new(100, 200, 50, 1),
+            HorzLine::new(200.4, 300, 50, 1),
+            // A thicker line on the same row, such as a beam, stays apart
+            HorzLine::new(150, 250, 50, 2),
+            HorzLine::new(0, 300, 60, 1),
+        ]);
+        recognizer.merge_horz_lines();
+        let mut lines = recognizer.parser.horz_lines.into_iter().collect::<Vec<_>>();
+        lines.sort_by_key(|line| (line.y, line.thickness));
+        assert_eq!(
+            lines,
+            vec![
+                HorzLine::new(0, 300, 50, 1),
+                HorzLine::new(150, 250, 50, 2),
+                HorzLine::new(0, 300, 60, 1),
+            ]
+        );
     }
 }
@@ -0,0 +1,22 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pitch {
+    // Diatonic steps from the middle C
+    pub step: i32,
+    pub alter: i8,
+}
+
+impl Pitch {
+    pub fn new(step: i32, alter: i8) -> Self {
+        Self { step, alter }
+    }
+
+    // 0: C, 1: D, ..., 6: B
+    pub fn degree(&self) -> usize {
+        self.step.rem_euclid(7) as usize
+    }
+
+    pub fn midi(&self) -> i8 {
+        let semitone = [0, 2, 4, 5, 7, 9, 11][self.degree()];
+        (0x3c + self.step.div_euclid(7) * 12 + semitone + i32::from(self.alter)) as i8
+    }
+}
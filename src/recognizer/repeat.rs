@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+
+use crate::common::{Object, Text, Type};
+use crate::recognizer::{Barline, Measure};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mark {
+    Segno,
+    // Both the jump to the coda and the coda itself
+    Coda,
+    Fine,
+    DaCapo,
+    DalSegno,
+}
+
+impl Mark {
+    // Marks in the bar, with the texts taken as marks
+    pub fn find(objects: &[Object], texts: &[Text]) -> (Vec<Mark>, Vec<usize>) {
+        let glyphs = objects.iter().filter_map(|obj| match obj.t {
+            Type::Segno => Some(Mark::Segno),
+            Type::Coda => Some(Mark::Coda),
+            _ => None,
+        });
+        let words = texts
+            .iter()
+            .enumerate()
+            .filter_map(|(index, text)| {
+                let text = text.text.trim().to_lowercase();
+                let mark = if text.starts_with("d.c.") || text.starts_with("da capo") {
+                    Mark::DaCapo
+                } else if text.starts_with("d.s.") || text.starts_with("dal segno") {
+                    Mark::DalSegno
+                } else if text == "fine" {
+                    Mark::Fine
+                } else if text.contains("coda") {
+                    Mark::Coda
+                } else {
+                    return None;
+                };
+                Some((index, mark))
+            })
+            .collect::<Vec<_>>();
+        let marks = glyphs.chain(words.iter().map(|(_, mark)| *mark)).collect();
+        (marks, words.into_iter().map(|(index, _)| index).collect())
+    }
+}
+
+// Playing order of the written measures, taking each repeat once; after D.C. or D.S.
+// repeats are not taken again and only the last ending is played
+pub fn unfold(measures: &[Measure]) -> Vec<usize> {
+    let last_ending = measures.iter().filter_map(|measure| measure.ending).max();
+    let has = |index: usize, mark: Mark| measures[index].marks.contains(&mark);
+    let mut order = vec![];
+    let mut taken = HashSet::new();
+    let (mut index, mut start, mut pass) = (0, 0, 1);
+    let mut returning = false;
+    let mut jumped = false;
+    while index < measures.len() && order.len() <= measures.len() * 4 {
+        let measure = &measures[index];
+        if measure.left == Barline::StartRepeat && !returning {
+            start = index;
+            pass = 1;
+        }
+        returning = false;
+        let skipped = match measure.ending {
+            Some(ending) if jumped => Some(ending) != last_ending,
+            Some(ending) => ending != pass,
+            None => false,
+        };
+        if skipped {
+            index += 1;
+            continue;
+        }
+        order.push(index);
+        if jumped && has(index, Mark::Fine) {
+            break;
+        }
+        if jumped && has(index, Mark::Coda) {
+            if let Some(coda) = (index + 1..measures.len()).find(|i| has(*i, Mark::Coda)) {
+                index = coda;
+                continue;
+            }
+        }
+        if measure.right == Barline::EndRepeat && !jumped && taken.insert(index) {
+            index = start;
+            pass += 1;
+            returning = true;
+            continue;
+        }
+        if !jumped && has(index, Mark::DaCapo) {
+            jumped = true;
+            index = 0;
+            continue;
+        }
+        if !jumped && has(index, Mark::DalSegno) {
+            jumped = true;
+            index = (0..measures.len())
+                .find(|i| has(*i, Mark::Segno))
+                .unwrap_or(0);
+            continue;
+        }
+        index += 1;
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Point;
+
+    fn measures(count: usize) -> Vec<Measure> {
+        (0..count).map(Measure::new).collect()
+    }
+
+    #[test]
+    fn plain_repeat() {
+        let mut measures = measures(4);
+        measures[1].left = Barline::StartRepeat;
+        measures[2].right = Barline::EndRepeat;
+        assert_eq!(unfold(&measures), vec![0, 1, 2, 1, 2, 3]);
+    }
+
+    #[test]
+    fn repeat_from_the_start() {
+        let mut measures = measures(3);
+        measures[1].right = Barline::EndRepeat;
+        assert_eq!(unfold(&measures), vec![0, 1, 0, 1, 2]);
+    }
+
+    #[test]
+    fn voltas() {
+        let mut measures = measures(5);
+        measures[0].left = Barline::StartRepeat;
+        measures[2].ending = Some(1);
+        measures[2].right = Barline::EndRepeat;
+        measures[3].ending = Some(2);
+        assert_eq!(unfold(&measures), vec![0, 1, 2, 0, 1, 3, 4]);
+    }
+
+    #[test]
+    fn da_capo_al_fine() {
+        let mut measures = measures(4);
+        measures[1].marks = vec![Mark::Fine];
+        measures[3].marks = vec![Mark::DaCapo];
+        assert_eq!(unfold(&measures), vec![0, 1, 2, 3, 0, 1]);
+    }
+
+    #[test]
+    fn dal_segno_al_coda() {
+        let mut measures = measures(6);
+        measures[1].marks = vec![Mark::Segno];
+        measures[2].marks = vec![Mark::Coda];
+        measures[3].marks = vec![Mark::DalSegno];
+        measures[5].marks = vec![Mark::Coda];
+        assert_eq!(unfold(&measures), vec![0, 1, 2, 3, 1, 2, 5]);
+    }
+
+    #[test]
+    fn marks_from_glyphs_and_words() {
+        let objects = [Object::new(Type::Segno, Point::new(10, 80))];
+        let texts = [
+            Text::new("D.S. al Coda".to_string(), Point::new(200, 150)),
+            Text::new("Fine".to_string(), Point::new(100, 150)),
+            Text::new("dolce".to_string(), Point::new(50, 150)),
+        ];
+        let (marks, claimed) = Mark::find(&objects, &texts);
+        assert_eq!(marks, vec![Mark::Segno, Mark::DalSegno, Mark::Fine]);
+        assert_eq!(claimed.len(), 2);
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashSet;
+
+use crate::common::{Fixed, HorzLine, VertLine};
+
+#[derive(Debug, Clone)]
+pub struct Staff {
+    pub x1: Fixed,
+    pub x2: Fixed,
+    pub y: Fixed,
+    pub lines: usize,
+    pub spacing: Fixed,
+}
+
+impl Staff {
+    pub fn new(x1: Fixed, x2: Fixed, y: Fixed, lines: usize, spacing: Fixed) -> Self {
+        Self {
+            x1,
+            x2,
+            y,
+            lines,
+            spacing,
+        }
+    }
+
+    pub fn height(&self) -> Fixed {
+        self.spacing * (self.lines as i64 - 1)
+    }
+
+    pub fn bottom(&self) -> Fixed {
+        self.y + self.height()
+    }
+
+    pub fn contains_x(&self, x: Fixed) -> bool {
+        self.x1 <= x && x <= self.x2
+    }
+
+    // Vertical gap between the staff and the range `y1..y2`, zero when they overlap
+    pub fn distance(&self, y1: Fixed, y2: Fixed) -> Fixed {
+        std::cmp::max(
+            Fixed::new(0),
+            std::cmp::max(y1 - self.bottom(), self.y - y2),
+        )
+    }
+
+    pub fn nearest(staves: &[Staff], y1: Fixed, y2: Fixed) -> usize {
+        (0..staves.len())
+            .min_by_key(|&i| staves[i].distance(y1, y2))
+            .unwrap()
+    }
+
+    // Barlines run across the whole staff, or well past a lone line
+    pub fn is_spanned(&self, line: &VertLine) -> bool {
+        let spans = if self.lines == 1 {
+            line.y1 <= self.y - self.spacing && self.y + self.spacing <= line.y2
+        } else {
+            line.y1 <= self.y + self.spacing / 4 && self.bottom() - self.spacing / 4 <= line.y2
+        };
+        spans && self.contains_x(line.x)
+    }
+
+    // Whether `line` runs through the gap between this staff and the one below
+    pub fn is_joined(&self, below: &Staff, line: &VertLine) -> bool {
+        line.y1 <= self.bottom() + self.spacing / 2
+            && below.y - below.spacing / 2 <= line.y2
+            && self.contains_x(line.x)
+            && below.contains_x(line.x)
+    }
+
+    // Staff lines share their extent, give or take the stroke caps
+    fn is_aligned(&self, line: &HorzLine) -> bool {
+        let tolerance = std::cmp::max(line.thickness * 2, line.len() / 100);
+        (self.x1 - line.x1).abs() <= tolerance && (self.x2 - line.x2).abs() <= tolerance
+    }
+
+    fn push(&mut self, line: &HorzLine) -> bool {
+        let gap = line.y - self.bottom();
+        if self.lines == 1 {
+            self.spacing = gap;
+        } else if (gap - self.spacing).abs() > self.spacing / 10 {
+            return false;
+        }
+        self.lines += 1;
+        true
+    }
+
+    // A lone line only counts as a (percussion) staff when a barline crosses it
+    fn is_single(&self, vert_lines: &HashSet<VertLine>) -> bool {
+        vert_lines
+            .iter()
+            .any(|line| line.y1 < self.y && self.y < line.y2 && self.contains_x(line.x))
+    }
+
+    pub fn owns(&self, line: &HorzLine) -> bool {
+        self.is_aligned(line) && self.y <= line.y && line.y <= self.bottom()
+    }
+
+    pub fn detect(horz_lines: &HashSet<HorzLine>, vert_lines: &HashSet<VertLine>) -> Vec<Staff> {
+        let longest = match horz_lines.iter().map(HorzLine::len).max() {
+            Some(longest) => longest,
+            None => return vec![],
+        };
+        let mut candidates = horz_lines
+            .iter()
+            .filter(|line| line.len() * 8 >= longest)
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|line| (line.y, line.x1));
+
+        let mut runs: Vec<Staff> = vec![];
+        for line in candidates {
+            let index = (0..runs.len())
+                .filter(|&i| {
+                    runs[i].is_aligned(line) && (line.y - runs[i].bottom()) * 25 <= longest
+                })
+                .max_by_key(|&i| runs[i].bottom());
+            if let Some(i) = index {
+                if runs[i].push(line) {
+                    continue;
+                }
+                if runs[i].lines == 2 {
+                    // The first line is a lone one, e.g. a bracket or a percussion staff
+                    let run = &mut runs[i];
+                    let mut rest = Staff::new(run.x1, run.x2, run.bottom(), 1, Fixed::new(0));
+                    run.lines = 1;
+                    if rest.push(line) {
+                        runs.push(rest);
+                        continue;
+                    }
+                }
+            }
+            runs.push(Staff::new(line.x1, line.x2, line.y, 1, Fixed::new(0)));
+        }
+
+        let mut spacings = runs
+            .iter()
+            .filter(|run| run.lines > 1)
+            .map(|run| run.spacing)
+            .collect::<Vec<_>>();
+        spacings.sort();
+        let spacing = spacings
+            .get(spacings.len() / 2)
+            .cloned()
+            .unwrap_or_else(|| longest / 100);
+
+        let mut staves = runs
+            .into_iter()
+            .filter_map(|mut run| match run.lines {
+                4..=6 => Some(run),
+                1 => {
+                    run.spacing = spacing;
+                    if run.is_single(vert_lines) {
+                        Some(run)
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        staves.sort_by_key(|staff| staff.y);
+        staves
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(x1: i64, x2: i64, y: i64, count: i64, spacing: i64) -> Vec<HorzLine> {
+        (0..count)
+            .map(|i| HorzLine::new(x1, x2, y + i * spacing, 1))
+            .collect()
+    }
+
+    #[test]
+    fn detect_indented_staves() {
+        let horz = lines(60, 560, 100, 5, 8)
+            .into_iter()
+            .chain(lines(40, 560, 200, 5, 8))
+            .collect::<HashSet<_>>();
+        let staves = Staff::detect(&horz, &HashSet::new());
+        assert_eq!(staves.len(), 2);
+        assert_eq!(staves[0].x1, Fixed::new(60));
+        assert_eq!(staves[0].y, Fixed::new(100));
+        assert_eq!(staves[0].lines, 5);
+        assert_eq!(staves[0].spacing, Fixed::new(8));
+        assert_eq!(staves[1].x1, Fixed::new(40));
+        assert_eq!(staves[1].bottom(), Fixed::new(232));
+    }
+
+    #[test]
+    fn detect_other_line_counts() {
+        let horz = lines(40, 560, 100, 4, 8)
+            .into_iter()
+            .chain(lines(40, 560, 200, 6, 8))
+            .collect::<HashSet<_>>();
+        let staves = Staff::detect(&horz, &HashSet::new());
+        assert_eq!(
+            staves.iter().map(|staff| staff.lines).collect::<Vec<_>>(),
+            vec![4, 6]
+        );
+    }
+
+    #[test]
+    fn detect_single_line_staff_by_barline() {
+        let horz = lines(40, 560, 100, 5, 8)
+            .into_iter()
+            .chain(lines(40, 560, 200, 1, 8))
+            .collect::<HashSet<_>>();
+        assert_eq!(Staff::detect(&horz, &HashSet::new()).len(), 1);
+        let vert = [VertLine::new(300, 190, 210)]
+            .iter()
+            .cloned()
+            .collect::<HashSet<_>>();
+        let staves = Staff::detect(&horz, &vert);
+        assert_eq!(staves.len(), 2);
+        assert_eq!(staves[1].lines, 1);
+        assert_eq!(staves[1].spacing, Fixed::new(8));
+    }
+
+    #[test]
+    fn detect_skips_short_lines() {
+        let horz = lines(40, 560, 100, 5, 8)
+            .into_iter()
+            .chain(lines(300, 330, 60, 5, 8))
+            .collect::<HashSet<_>>();
+        assert_eq!(Staff::detect(&horz, &HashSet::new()).len(), 1);
+    }
+
+    #[test]
+    fn joined_through_the_gap() {
+        let upper = Staff::new(
+            Fixed::new(40),
+            Fixed::new(560),
+            Fixed::new(100),
+            5,
+            Fixed::new(8),
+        );
+        let lower = Staff::new(
+            Fixed::new(40),
+            Fixed::new(560),
+            Fixed::new(160),
+            5,
+            Fixed::new(8),
+        );
+        assert!(upper.is_joined(&lower, &VertLine::new(40, 100, 192)));
+        assert!(!upper.is_joined(&lower, &VertLine::new(300, 100, 132)));
+    }
+}
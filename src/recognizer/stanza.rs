@@ -1,53 +1,172 @@
-use crate::common::{Fixed, Object, VertLine};
-use crate::recognizer::Bar;
+use std::collections::{HashMap, HashSet};
 
+use crate::common::{
+    Articulation, Curve, Direction, Dynamic, Fixed, HorzLine, Line, Object, Point, Quadrangle,
+    Text, Type, VertLine,
+};
+use crate::recognizer::{
+    Arc, Bar, Barline, Beam, Clef, Error, Grace, Hairpin, Harmony, Key, Lyric, Mark, Staff, Tempo,
+    TimeSignature, Tuplet, Wedge,
+};
+
+use crate::recognizer::bar::Stems;
 use crate::recognizer::Collector;
 
 #[derive(Debug)]
 pub struct Stanza {
-    x: Fixed,
     pub y: Fixed,
-    width: Fixed,
-    pub height: Fixed,
     pub scale: Fixed,
+    pub staves: Vec<Staff>,
     pub bars: Vec<Bar>,
+    pub beams: Vec<Beam>,
+    pub arcs: Vec<Arc>,
+    pub ledgers: Vec<HorzLine>,
+    pub voltas: Vec<HorzLine>,
+    // Tuplet brackets, halves broken around their number joined
+    pub brackets: Vec<HorzLine>,
+    pub hairpins: Vec<Hairpin>,
+    // Strokes through the stems of grace notes
+    pub slashes: Vec<Line>,
+    // Lines holding a lyric syllable over several notes
+    pub extenders: Vec<HorzLine>,
 
     head_size: Option<Fixed>, // Config
 }
 
 impl Stanza {
-    pub fn new(x: Fixed, y: Fixed, width: Fixed, height: Fixed, scale: Fixed) -> Self {
+    pub fn new(staves: Vec<Staff>) -> Self {
         Self {
-            x,
-            y,
-            width,
-            height,
-            scale,
+            y: staves.first().unwrap().y,
+            scale: staves.first().unwrap().spacing,
+            staves,
             bars: vec![],
+            beams: vec![],
+            arcs: vec![],
+            ledgers: vec![],
+            voltas: vec![],
+            brackets: vec![],
+            hairpins: vec![],
+            slashes: vec![],
+            extenders: vec![],
             head_size: None,
         }
     }
 
-    pub fn insert_bar(&mut self, vert_line: &VertLine) -> bool {
-        if vert_line.y1 == self.y {
-            assert!(self.y + self.height == vert_line.y2);
-            self.bars.push(Bar::new(vert_line.x));
-            true
-        } else {
-            false
+    // Lines a space apart or less make up one barline, thick ones are filled quadrangles
+    pub fn insert_bar(&mut self, vert_line: &VertLine, width: Fixed) -> bool {
+        if !self.staves.iter().any(|staff| staff.is_spanned(vert_line)) {
+            return false;
+        }
+        let thick = width > self.scale / 4;
+        let index = match self
+            .bars
+            .iter()
+            .position(|bar| bar.is_near(vert_line.x, self.scale))
+        {
+            Some(index) => index,
+            None => {
+                self.bars.push(Bar::new(vert_line.x));
+                self.bars.len() - 1
+            }
+        };
+        // Barlines broken between staff groups are drawn as several segments
+        self.bars[index].push_line(vert_line.x, thick, self.scale / 4);
+        true
+    }
+
+    // Dashed barlines are columns of short segments running down a staff
+    pub fn insert_dashes(&mut self, lines: &HashSet<VertLine>) -> Vec<VertLine> {
+        let mut columns: HashMap<Fixed, Vec<&VertLine>> = HashMap::new();
+        for line in lines.iter().filter(|line| line.y2 - line.y1 < self.scale) {
+            columns.entry(line.x).or_default().push(line);
         }
+        let mut dashes = vec![];
+        for (x, column) in columns {
+            let y1 = column.iter().map(|line| line.y1).min().unwrap();
+            let y2 = column.iter().map(|line| line.y2).max().unwrap();
+            let dashed = self.staves.iter().any(|staff| {
+                staff.lines > 1
+                    && staff.contains_x(x)
+                    && staff.y - self.scale / 4 <= y1
+                    && y2 <= staff.bottom() + self.scale / 4
+                    && y2 - y1 >= staff.height() * 0.75
+            });
+            if column.len() >= 3 && dashed {
+                if !self.bars.iter().any(|bar| bar.is_near(x, self.scale)) {
+                    self.bars.push(Bar::dashed(x));
+                }
+                dashes.extend(column.into_iter().cloned());
+            }
+        }
+        dashes
     }
 
+    // Systems without a barline in front get one at the start of the staves,
+    // the barline at their far end closes the last measure
     pub fn sort_bars(&mut self) {
         self.bars.sort();
-        self.bars.pop();
+        let x1 = self.staves.iter().map(|staff| staff.x1).min().unwrap();
+        let x2 = self.staves.iter().map(|staff| staff.x2).max().unwrap();
+        if self
+            .bars
+            .first()
+            .is_none_or(|bar| bar.x > x1 + self.scale * 2)
+        {
+            self.bars.insert(0, Bar::new(x1));
+        }
+        if let Some(last) = self.bars.last_mut() {
+            if last.x >= x2 - self.scale * 2 {
+                last.closing = true;
+            }
+        }
     }
 
+    // Repeat dots come in pairs, one in each middle space at the same x
+    pub fn put_repeat_dot(&mut self, obj: &Object, dots: &[Object]) -> bool {
+        if !matches!(obj.t, Type::Dot) {
+            return false;
+        }
+        let staff = &self.staves[Staff::nearest(&self.staves, obj.point.y, obj.point.y)];
+        let middle = staff.y + staff.height() / 2;
+        let offset = (obj.point.y - middle).abs();
+        if staff.lines < 5 || (offset - staff.spacing / 2).abs() > staff.spacing / 4 {
+            return false;
+        }
+        let mirrored = middle + middle - obj.point.y;
+        let paired = dots.iter().any(|dot| {
+            (dot.point.x - obj.point.x).abs() <= staff.spacing / 4
+                && (dot.point.y - mirrored).abs() <= staff.spacing / 4
+        });
+        if !paired {
+            return false;
+        }
+        let reach = self.scale * 1.5;
+        self.bars
+            .iter_mut()
+            .any(|bar| bar.put_dot(obj.point.x, reach))
+    }
+
+    // Marks above the top staff still belong to it
     pub fn put_object(&mut self, obj: &Object) -> bool {
-        if self.y < obj.point.y {
+        let mut obj = obj.clone();
+        if let Type::Rest(1) = obj.t {
+            obj.t = self.hanging(&obj.point);
+        }
+        self.insert_object(obj)
+    }
+
+    // Marks above the music, metronome notes with their numbers among them, reach as
+    // high for glyphs as for texts
+    fn reaches(&self, point: &Point) -> bool {
+        self.y - self.scale * 8 < point.y
+            && self.staves.iter().any(|staff| staff.contains_x(point.x))
+    }
+
+    fn insert_object(&mut self, obj: Object) -> bool {
+        if self.reaches(&obj.point) {
             for bar in self.bars.iter_mut().rev() {
                 if bar.x < obj.point.x {
-                    bar.store.push(obj.clone());
+                    bar.store.push(obj);
                     break;
                 }
             }
@@ -57,130 +176,1551 @@ impl Stanza {
         }
     }
 
-    fn is_high(&self, stem: &VertLine) -> bool {
-        let mut points = vec![self.y, self.y + self.scale * 5, stem.y1, stem.y2];
-        let length = stem.y2 - stem.y1 + self.scale * 5;
-        points.sort();
-        *points.last().unwrap() - *points.first().unwrap() < length
-    }
-
-    fn is_low(&self, stem: &VertLine) -> bool {
-        let mut points = vec![
-            self.y + self.height,
-            self.y + self.height - self.scale * 5,
-            stem.y1,
-            stem.y2,
-        ];
-        let length = stem.y2 - stem.y1 + self.scale * 5;
-        points.sort();
-        *points.last().unwrap() - *points.first().unwrap() < length
+    // Whole and half rests share a glyph, drawn from the line it hangs from, the fourth,
+    // or sits on, the middle one
+    fn hanging(&self, point: &Point) -> Type {
+        let staff = &self.staves[Staff::nearest(&self.staves, point.y, point.y)];
+        if point.y - staff.y < staff.spacing * 1.5 {
+            Type::Rest(1)
+        } else {
+            Type::Rest(2)
+        }
     }
 
-    fn is_mid(&self, stem: &VertLine) -> bool {
-        let mut points = vec![
-            self.y + self.scale * 5,
-            self.y + self.height - self.scale * 5,
-            stem.y1,
-            stem.y2,
-        ];
-        let length = stem.y2 - stem.y1 + self.scale * 5;
-        points.sort();
-        *points.last().unwrap() - *points.first().unwrap() < length
+    pub fn put_text(&mut self, text: &Text) -> bool {
+        if self.reaches(&text.point) {
+            if let Some(bar) = self.bars.iter_mut().rev().find(|bar| bar.x < text.point.x) {
+                bar.texts.push(text.clone());
+            }
+            true
+        } else {
+            false
+        }
     }
 
     pub fn put_stem(&mut self, stem: &VertLine) -> bool {
-        let high = self.is_high(stem);
-        let low = self.is_low(stem);
-        let mid = self.is_mid(stem);
-        assert!(!high || !low, "It cannot be both!");
-        if !high && !low && !mid {
+        let staff = Staff::nearest(&self.staves, stem.y1, stem.y2);
+        if self.staves[staff].distance(stem.y1, stem.y2) > self.scale * 4 {
             return false;
         }
         for bar in self.bars.iter_mut().rev() {
             if bar.x < stem.x {
-                if high {
-                    bar.stems.push_high(stem);
+                bar.stems.push(staff, stem);
+                return true;
+            }
+        }
+        false
+    }
+
+    // Filled blocks inside the staff are rests: whole rests hang from a line and half rests
+    // sit on one, long ones across the middle line are multi-measure rests. Rests are
+    // level rectangles; blocks a stem ends on are left for the beams
+    pub fn put_block(&mut self, quadra: &Quadrangle) -> bool {
+        let x1 = quadra.points.iter().map(|p| p.x).min().unwrap();
+        let x2 = quadra.points.iter().map(|p| p.x).max().unwrap();
+        let y1 = quadra.points.iter().map(|p| p.y).min().unwrap();
+        let y2 = quadra.points.iter().map(|p| p.y).max().unwrap();
+        let staff = &self.staves[Staff::nearest(&self.staves, y1, y2)];
+        let (spacing, middle) = (staff.spacing, staff.y + staff.height() / 2);
+        let tolerance = spacing / 10;
+        let level = quadra.points.iter().all(|p| {
+            ((p.y - y1).abs() <= tolerance || (p.y - y2).abs() <= tolerance)
+                && ((p.x - x1).abs() <= tolerance || (p.x - x2).abs() <= tolerance)
+        });
+        let stemmed = self
+            .bars
+            .iter()
+            .rev()
+            .find(|bar| bar.x < x1)
+            .is_some_and(|bar| bar.stems.ends_on(x1, x2, y1, y2, spacing / 4));
+        if !level || stemmed {
+            return false;
+        }
+        let on_line = |y: Fixed| {
+            let lines = (y - staff.y) / spacing;
+            (lines - lines.round()).abs() < 0.15
+        };
+        let t = if x2 - x1 >= spacing * 3
+            && spacing / 2 <= y2 - y1
+            && y2 - y1 <= spacing * 1.2
+            && ((y1 + y2) / 2 - middle).abs() < spacing / 2
+        {
+            Type::Multirest
+        } else if spacing * 0.6 <= x2 - x1
+            && x2 - x1 <= spacing * 2
+            && spacing * 0.3 <= y2 - y1
+            && y2 - y1 <= spacing * 0.75
+            && staff.distance(y1, y2) <= spacing
+        {
+            if on_line(y1) {
+                Type::Rest(1)
+            } else if on_line(y2) {
+                Type::Rest(2)
+            } else {
+                return false;
+            }
+        } else {
+            return false;
+        };
+        self.insert_object(Object::new(t, Point::new((x1 + x2) / 2, (y1 + y2) / 2)))
+    }
+
+    // The number of bars stands above the H-bar, one digit after another
+    fn count(store: &[Object], multirest: &Object, staff: &Staff) -> u32 {
+        let mut digits = store
+            .iter()
+            .filter_map(|obj| match obj.t {
+                Type::Digit(digit)
+                    if (obj.point.x - multirest.point.x).abs() < staff.spacing * 3
+                        && obj.point.y < staff.y
+                        && staff.y - obj.point.y < staff.spacing * 4 =>
+                {
+                    Some((obj.point.x, digit))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        digits.sort_by_key(|(x, _)| *x);
+        digits
+            .iter()
+            .fold(0, |count, (_, digit)| count * 10 + u32::from(*digit))
+            .max(1)
+    }
+
+    pub fn put_beam(&mut self, quadra: &Quadrangle) -> bool {
+        match Beam::new(quadra) {
+            Some(beam) if self.y - self.scale * 8 < beam.y1 => {
+                self.beams.push(beam);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn put_arc(&mut self, curve: &Curve) -> bool {
+        match Arc::new(curve) {
+            Some(arc) => {
+                let (y1, y2) = (arc.y1.min(arc.y2), arc.y1.max(arc.y2));
+                let staff = Staff::nearest(&self.staves, y1, y2);
+                if self.staves[staff].distance(y1, y2) > self.scale * 4 {
+                    return false;
+                }
+                self.arcs.push(arc);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Ledger lines are short and lie a whole number of spaces off the staff
+    pub fn put_ledger(&mut self, line: &HorzLine) -> bool {
+        let staff = &self.staves[Staff::nearest(&self.staves, line.y, line.y)];
+        let spaces = staff.distance(line.y, line.y) / staff.spacing;
+        if staff.lines == 1
+            || !staff.contains_x(line.x1)
+            || line.len() > staff.spacing * 4
+            || !(0.75..=8.25).contains(&spaces)
+            || (spaces - spaces.round()).abs() > 0.25
+        {
+            return false;
+        }
+        self.ledgers.push(line.clone());
+        true
+    }
+
+    // Hairpins lie under or between the staves they belong to
+    pub fn put_hairpin(&mut self, hairpin: &Hairpin) -> bool {
+        let staff = &self.staves[Staff::nearest(&self.staves, hairpin.y, hairpin.y)];
+        if staff.contains_x(hairpin.x1) && staff.distance(hairpin.y, hairpin.y) <= self.scale * 8 {
+            self.hairpins.push(hairpin.clone());
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn put_slash(&mut self, line: &Line) -> bool {
+        let (dx, dy) = ((line.x2 - line.x1).abs(), (line.y2 - line.y1).abs());
+        let staff = &self.staves[Staff::nearest(&self.staves, line.y1, line.y2)];
+        if dx > Fixed::new(0)
+            && dy > Fixed::new(0)
+            && dx < self.scale * 3
+            && dy < self.scale * 3
+            && staff.contains_x(line.x1)
+            && staff.distance(line.y1, line.y2) <= self.scale * 6
+        {
+            self.slashes.push(line.clone());
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn put_extender(&mut self, line: &HorzLine) -> bool {
+        if Lyric::is_extender(&self.staves, line) {
+            self.extenders.push(line.clone());
+            true
+        } else {
+            false
+        }
+    }
+
+    // Volta brackets run well above the top staff of the system
+    pub fn put_volta(&mut self, line: &HorzLine) -> bool {
+        let staff = &self.staves[0];
+        let above = staff.y - line.y;
+        if staff.contains_x(line.x1)
+            && line.len() >= staff.spacing * 2
+            && staff.spacing * 1.5 <= above
+            && above <= staff.spacing * 8
+        {
+            self.voltas.push(line.clone());
+            true
+        } else {
+            false
+        }
+    }
+
+    // Tuplet brackets are thin lines off the staff with a small number by their middle,
+    // which crosses no head the way a ledger line does
+    pub fn put_bracket(&mut self, line: &HorzLine) -> bool {
+        let scale = self.scale;
+        let staff = &self.staves[Staff::nearest(&self.staves, line.y, line.y)];
+        let mut objects = self.bars.iter().flat_map(|bar| bar.store.iter());
+        let numbered = objects.clone().any(|obj| {
+            matches!(obj.t, Type::Digit(_))
+                && obj.small
+                && (obj.point.y - line.y).abs() <= scale * 2
+                && line.x1 - scale * 2 <= obj.point.x
+                && obj.point.x <= line.x2 + scale * 2
+        });
+        let crossing = objects.any(|obj| {
+            matches!(obj.t, Type::Head(_))
+                && line.x1 <= obj.point.x
+                && obj.point.x <= line.x2
+                && (obj.point.y - line.y).abs() <= scale
+        });
+        if !staff.contains_x(line.x1)
+            || line.thickness > scale / 4
+            || staff.distance(line.y, line.y) < scale
+            || !numbered
+            || crossing
+        {
+            return false;
+        }
+        match self.brackets.iter_mut().find(|bracket| {
+            (bracket.y - line.y).abs() <= scale / 4
+                && bracket.x1.max(line.x1) - bracket.x2.min(line.x2) <= scale * 3
+        }) {
+            Some(bracket) => {
+                bracket.x1 = bracket.x1.min(line.x1);
+                bracket.x2 = bracket.x2.max(line.x2);
+            }
+            None => self.brackets.push(line.clone()),
+        }
+        true
+    }
+
+    // Volta numbers hang under the start of the bracket
+    fn numbers(volta: &HorzLine, point: &Point, scale: Fixed) -> bool {
+        let (dx, dy) = (point.x - volta.x1, point.y - volta.y);
+        Fixed::new(0) <= dx && dx <= scale * 3 && Fixed::new(0) <= dy && dy <= scale * 3
+    }
+
+    fn ending(&self, volta: &HorzLine) -> Option<u8> {
+        let near = |point: &Point| Self::numbers(volta, point, self.scale);
+        self.bars.iter().find_map(|bar| {
+            let digit = bar.store.iter().find_map(|obj| match obj.t {
+                Type::Digit(digit) if near(&obj.point) => Some(digit),
+                _ => None,
+            });
+            let text = bar
+                .texts
+                .iter()
+                .find_map(|text| match text.text.trim().chars().next() {
+                    Some(c) if near(&text.point) => c.to_digit(10).map(|digit| digit as u8),
+                    _ => None,
+                });
+            digit.or(text)
+        })
+    }
+
+    // Dynamics go to the closest head of the staff they are nearest to; hairpins start at
+    // the first head they reach and stop at the last one
+    #[allow(clippy::type_complexity)]
+    fn attach_dynamics(
+        bar: &Bar,
+        end: Fixed,
+        channels: &[usize],
+        staves: &[Staff],
+        hairpins: &[Hairpin],
+    ) -> (HashMap<usize, Dynamic>, HashMap<usize, Vec<Wedge>>) {
+        let heads = bar
+            .store
+            .iter()
+            .enumerate()
+            .filter(|(_, obj)| matches!(obj.t, Type::Head(_)))
+            .collect::<Vec<_>>();
+        let marks = bar
+            .store
+            .iter()
+            .filter_map(|obj| match obj.t {
+                Type::Dynamic(dynamic) => Some((&obj.point, dynamic)),
+                _ => None,
+            })
+            .chain(bar.texts.iter().filter_map(|text| {
+                Dynamic::parse(text.text.trim()).map(|dynamic| (&text.point, dynamic))
+            }));
+        let mut dynamics = HashMap::new();
+        for (point, dynamic) in marks {
+            let channel = Staff::nearest(staves, point.y, point.y);
+            let head = heads
+                .iter()
+                .filter(|(index, head)| {
+                    channels[*index] == channel
+                        && (head.point.x - point.x).abs() <= staves[channel].spacing * 4
+                })
+                .min_by_key(|(_, head)| (head.point.x - point.x).abs());
+            if let Some((index, _)) = head {
+                dynamics.insert(*index, dynamic);
+            }
+        }
+        let mut wedges: HashMap<usize, Vec<Wedge>> = HashMap::new();
+        for hairpin in hairpins {
+            let channel = Staff::nearest(staves, hairpin.y, hairpin.y);
+            let spacing = staves[channel].spacing;
+            let mut reached = heads
+                .iter()
+                .filter(|(index, head)| {
+                    channels[*index] == channel
+                        && hairpin.x1 - spacing <= head.point.x
+                        && head.point.x <= hairpin.x2 + spacing
+                })
+                .map(|(index, _)| *index);
+            if bar.x <= hairpin.x1 && hairpin.x1 < end {
+                if let Some(first) = reached.clone().next() {
+                    let wedge = if hairpin.crescendo {
+                        Wedge::Crescendo
+                    } else {
+                        Wedge::Diminuendo
+                    };
+                    wedges.entry(first).or_default().push(wedge);
                 }
-                if low {
-                    bar.stems.push_low(stem);
+            }
+            if bar.x <= hairpin.x2 && hairpin.x2 < end {
+                if let Some(last) = reached.next_back() {
+                    wedges.entry(last).or_default().push(Wedge::Stop);
                 }
-                if !high && !low {
-                    bar.stems.push_mid(stem);
+            }
+        }
+        (dynamics, wedges)
+    }
+
+    // Heads off the staff take their position from the ledger lines under them, counted
+    // outwards from the staff, rather than from their distance to it
+    fn attach_ledgers(
+        store: &[Object],
+        channels: &[usize],
+        staves: &[Staff],
+        ledgers: &[HorzLine],
+    ) -> HashMap<usize, Fixed> {
+        let mut res = HashMap::new();
+        for (index, head) in store.iter().enumerate() {
+            let staff = &staves[channels[index]];
+            let (x, y) = (head.point.x, head.point.y);
+            if !matches!(head.t, Type::Head(_)) || staff.distance(y, y) == Fixed::new(0) {
+                continue;
+            }
+            let above = y < staff.y;
+            let mut under = ledgers
+                .iter()
+                .filter(|ledger| {
+                    ledger.x1 - staff.spacing / 2 <= x
+                        && x <= ledger.x2
+                        && (ledger.y < staff.y) == above
+                        && Staff::nearest(staves, ledger.y, ledger.y) == channels[index]
+                })
+                .collect::<Vec<_>>();
+            under.sort_by_key(|ledger| staff.distance(ledger.y, ledger.y));
+            let nearest = under
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, ledger)| (ledger.y - y).abs());
+            if let Some((i, ledger)) = nearest {
+                let line = if above {
+                    2 * (staff.lines as i64 - 1) + 2 * (i as i64 + 1)
+                } else {
+                    -2 * (i as i64 + 1)
+                };
+                let position = line + ((ledger.y - y) / staff.spacing * 2.).round() as i64;
+                res.insert(index, staff.bottom() - staff.spacing * position / 2);
+            }
+        }
+        res
+    }
+
+    // Accidentals belong to the closest notehead on their right at the same staff position
+    fn attach_accidentals(
+        store: &[Object],
+        channels: &[usize],
+        staves: &[Staff],
+    ) -> HashSet<usize> {
+        let mut attached = HashSet::new();
+        for (index, obj) in store.iter().enumerate() {
+            if let Type::Accidental(_) = obj.t {
+                let staff = &staves[channels[index]];
+                let position = Clef::position(staff, obj.point.y);
+                let head = store[index + 1..]
+                    .iter()
+                    .zip(channels[index + 1..].iter())
+                    .take_while(|(head, _)| head.point.x - obj.point.x < staff.spacing * 4)
+                    .any(|(head, channel)| {
+                        matches!(head.t, Type::Head(_))
+                            && *channel == channels[index]
+                            && Clef::position(staff, head.point.y) == position
+                    });
+                if head {
+                    attached.insert(index);
                 }
-                return true;
             }
         }
-        false
+        attached
     }
 
-    pub fn process(&mut self) {
-        use crate::common::Type;
+    // Articulations sit in line with the head on the side away from the stem, fermatas
+    // further out over the chord or rest; a dot straight above or below a head is a
+    // staccato. Returns the articulations by head or rest and the dots taken as staccatos
+    fn attach_articulations(
+        store: &[Object],
+        channels: &[usize],
+        staves: &[Staff],
+    ) -> (HashMap<usize, Vec<Articulation>>, HashSet<usize>) {
+        let mut articulations: HashMap<usize, Vec<Articulation>> = HashMap::new();
+        let mut staccatos = HashSet::new();
+        for (index, mark) in store.iter().enumerate() {
+            let spacing = staves[channels[index]].spacing;
+            let articulation = match mark.t {
+                Type::Articulation(articulation) => articulation,
+                Type::Dot => Articulation::Staccato,
+                _ => continue,
+            };
+            let (reach, height) = match articulation {
+                Articulation::Staccato => (spacing / 2, spacing * 5 / 2),
+                Articulation::Fermata => (spacing * 2, spacing * 12),
+                _ => (spacing, spacing * 4),
+            };
+            let head = store
+                .iter()
+                .enumerate()
+                .filter(|(i, obj)| {
+                    let (dx, dy) = (mark.point.x - obj.point.x, mark.point.y - obj.point.y);
+                    let target = match obj.t {
+                        Type::Head(_) => true,
+                        Type::Rest(_) => articulation == Articulation::Fermata,
+                        _ => false,
+                    };
+                    target
+                        && channels[*i] == channels[index]
+                        && dx.abs() <= reach
+                        && spacing / 2 < dy.abs()
+                        && dy.abs() <= height
+                })
+                .min_by_key(|(_, obj)| (mark.point.y - obj.point.y).abs())
+                .map(|(i, _)| i);
+            if let Some(head) = head {
+                articulations.entry(head).or_default().push(articulation);
+                if let Type::Dot = mark.t {
+                    staccatos.insert(index);
+                }
+            }
+        }
+        (articulations, staccatos)
+    }
 
-        let borders = [
-            self.y + self.height - self.scale * 6,
-            self.y + self.scale * 5,
-        ];
-        let mut collectors = [Collector::new(), Collector::new()];
+    // Small heads leading straight into a full-size note are grace notes, slashed when a
+    // stroke crosses their stem; the others are cue notes
+    fn attach_graces(
+        store: &[Object],
+        channels: &[usize],
+        staves: &[Staff],
+        stems: &HashMap<usize, VertLine>,
+        slashes: &[Line],
+    ) -> HashMap<usize, Option<Grace>> {
+        let mut graces = HashMap::new();
+        for (index, head) in store.iter().enumerate() {
+            if !head.small || !matches!(head.t, Type::Head(_)) {
+                continue;
+            }
+            let spacing = staves[channels[index]].spacing;
+            let mut last = head.point.x;
+            let mut principal = false;
+            for (i, obj) in store.iter().enumerate().skip(index + 1) {
+                if channels[i] != channels[index] || !matches!(obj.t, Type::Head(_) | Type::Rest(_))
+                {
+                    continue;
+                }
+                if obj.point.x - last > spacing * 5 {
+                    break;
+                }
+                if !obj.small {
+                    principal = matches!(obj.t, Type::Head(_));
+                    break;
+                }
+                last = obj.point.x;
+            }
+            let grace = if !principal {
+                None
+            } else if stems.get(&index).is_some_and(|stem| {
+                slashes.iter().any(|slash| {
+                    slash.x1.min(slash.x2) <= stem.x
+                        && stem.x <= slash.x1.max(slash.x2)
+                        && slash.y1.min(slash.y2) <= stem.y2
+                        && stem.y1 <= slash.y1.max(slash.y2)
+                })
+            }) {
+                Some(Grace::Acciaccatura)
+            } else {
+                Some(Grace::Appoggiatura)
+            };
+            graces.insert(index, grace);
+        }
+        graces
+    }
+
+    // Tempo marks apply from the note under or just after them, on whichever staff
+    fn attach_tempos(
+        store: &[Object],
+        channels: &[usize],
+        skipped: &HashSet<usize>,
+        tempos: Vec<(Point, Tempo)>,
+        scale: Fixed,
+    ) -> HashMap<usize, Tempo> {
+        let mut attached = HashMap::new();
+        for (point, tempo) in tempos {
+            let head = store
+                .iter()
+                .enumerate()
+                .filter(|(index, obj)| {
+                    matches!(obj.t, Type::Head(_)) && !obj.small && !skipped.contains(index)
+                })
+                .min_by_key(|(index, obj)| {
+                    let dx = obj.point.x - point.x;
+                    (
+                        obj.point.x < point.x - scale * 2,
+                        dx.abs(),
+                        channels[*index],
+                    )
+                });
+            if let Some((index, _)) = head {
+                attached.insert(index, tempo);
+            }
+        }
+        attached
+    }
 
-        for bar in self.bars.iter_mut() {
+    // Syllables go to the head they are centred under, on the staff above them
+    fn attach_lyrics(
+        store: &[Object],
+        channels: &[usize],
+        skipped: &HashSet<usize>,
+        syllables: &[&(usize, Point, Lyric)],
+        scale: Fixed,
+    ) -> HashMap<usize, Vec<Lyric>> {
+        let mut attached: HashMap<usize, Vec<Lyric>> = HashMap::new();
+        for (staff, point, lyric) in syllables.iter().copied() {
+            let centre = point.x + scale * 0.3 * lyric.text.chars().count() as i64;
+            let head = store
+                .iter()
+                .enumerate()
+                .filter(|(index, obj)| {
+                    matches!(obj.t, Type::Head(_))
+                        && !obj.small
+                        && channels[*index] == *staff
+                        && !skipped.contains(index)
+                })
+                .min_by_key(|(_, obj)| (obj.point.x - centre).abs());
+            if let Some((index, _)) = head {
+                attached.entry(index).or_default().push(lyric.clone());
+            }
+        }
+        attached
+    }
+
+    // Chord symbols start with the note or rest they are set over
+    fn attach_harmonies(
+        store: &[Object],
+        channels: &[usize],
+        skipped: &HashSet<usize>,
+        harmonies: Vec<(usize, Point, Harmony)>,
+    ) -> HashMap<usize, Harmony> {
+        let mut attached = HashMap::new();
+        for (staff, point, harmony) in harmonies {
+            let target = store
+                .iter()
+                .enumerate()
+                .filter(|(index, obj)| {
+                    matches!(obj.t, Type::Head(_) | Type::Rest(_) | Type::Multirest)
+                        && !obj.small
+                        && channels[*index] == staff
+                        && !skipped.contains(index)
+                })
+                .min_by_key(|(_, obj)| (obj.point.x - point.x).abs());
+            if let Some((index, _)) = target {
+                attached.insert(index, harmony);
+            }
+        }
+        attached
+    }
+
+    // Augmentation dots sit in the space right of a note or rest, one column per dot;
+    // staccato dots above or below the head are not far enough to the right
+    fn attach_dots(
+        store: &[Object],
+        channels: &[usize],
+        staves: &[Staff],
+        staccatos: &HashSet<usize>,
+    ) -> HashMap<usize, u8> {
+        let mut columns: HashMap<usize, Vec<Fixed>> = HashMap::new();
+        for (index, dot) in store.iter().enumerate() {
+            if let (Type::Dot, false) = (&dot.t, staccatos.contains(&index)) {
+                let spacing = staves[channels[index]].spacing;
+                let target = store[..index]
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, obj)| {
+                        let (dx, dy) = (dot.point.x - obj.point.x, dot.point.y - obj.point.y);
+                        let reach = match obj.t {
+                            Type::Head(_) => spacing * 3 / 4,
+                            Type::Rest(_) => spacing,
+                            _ => return false,
+                        };
+                        channels[*i] == channels[index]
+                            && spacing < dx
+                            && dx < spacing * 3
+                            && dy.abs() <= reach
+                    })
+                    .min_by_key(|(_, obj)| dot.point.x - obj.point.x)
+                    .map(|(i, _)| i);
+                if let Some(target) = target {
+                    let column = columns.entry(target).or_default();
+                    if column
+                        .iter()
+                        .all(|x| (*x - dot.point.x).abs() > spacing / 4)
+                    {
+                        column.push(dot.point.x);
+                    }
+                }
+            }
+        }
+        columns
+            .into_iter()
+            .map(|(target, column)| (target, column.len() as u8))
+            .collect()
+    }
+
+    // Each arc leaves the closest head at its left end and reaches the closest one at its right;
+    // heads are mapped to the arcs they start and end
+    fn attach_arcs(
+        store: &[Object],
+        channels: &[usize],
+        staves: &[Staff],
+        arcs: &[Arc],
+    ) -> (HashMap<usize, Vec<usize>>, HashMap<usize, Vec<usize>>) {
+        let mut starts: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut ends: HashMap<usize, Vec<usize>> = HashMap::new();
+        let heads = store
+            .iter()
+            .enumerate()
+            .filter(|(_, obj)| matches!(obj.t, Type::Head(_)))
+            .collect::<Vec<_>>();
+        for (index, arc) in arcs.iter().enumerate() {
+            let start = heads
+                .iter()
+                .filter_map(|(i, head)| {
+                    arc.starts_at(head, staves[channels[*i]].spacing)
+                        .map(|distance| (distance, *i))
+                })
+                .min();
+            if let Some((_, head)) = start {
+                starts.entry(head).or_default().push(index);
+            }
+            let end = heads
+                .iter()
+                .filter_map(|(i, head)| {
+                    arc.ends_at(head, staves[channels[*i]].spacing)
+                        .map(|distance| (distance, *i))
+                })
+                .min();
+            if let Some((_, head)) = end {
+                ends.entry(head).or_default().push(index);
+            }
+        }
+        (starts, ends)
+    }
+
+    // Where stems go both ways at once, up stems are the upper voice and down stems the
+    // lower one, a beam keeping the stems it joins in the voice most of them point to;
+    // stemless notes and rests go to the voice with nothing sounding at the same time,
+    // or else by their side of the middle line
+    fn separate(
+        store: &[Object],
+        channels: &[usize],
+        staves: &[Staff],
+        stems: &HashMap<usize, VertLine>,
+        beams: &[Beam],
+    ) -> Vec<u8> {
+        let mut directions = stems
+            .iter()
+            .map(|(index, stem)| (*index, Stems::direction(stem, &store[*index])))
+            .collect::<HashMap<_, _>>();
+        for beam in beams {
+            let joined = stems
+                .iter()
+                .filter(|(_, stem)| beam.touches(stem))
+                .map(|(index, _)| *index)
+                .collect::<Vec<_>>();
+            let up = joined
+                .iter()
+                .filter(|index| directions[index] == Direction::Up)
+                .count();
+            let direction = if up * 2 >= joined.len() {
+                Direction::Up
+            } else {
+                Direction::Down
+            };
+            for index in joined {
+                directions.insert(index, direction);
+            }
+        }
+        let polyphonic = (0..staves.len())
+            .map(|channel| {
+                directions.iter().any(|(i, up)| {
+                    directions.iter().any(|(j, down)| {
+                        channels[*i] == channel
+                            && channels[*j] == channel
+                            && *up == Direction::Up
+                            && *down == Direction::Down
+                            && (store[*i].point.x - store[*j].point.x).abs()
+                                <= staves[channel].spacing * 2
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let voice = |direction: &Direction| match direction {
+            Direction::Up => 1,
+            Direction::Down => 2,
+        };
+        store
+            .iter()
+            .enumerate()
+            .map(|(index, obj)| {
+                let channel = channels[index];
+                let staff = &staves[channel];
+                if !polyphonic[channel] {
+                    return 1;
+                }
+                if let Some(direction) = directions.get(&index) {
+                    return voice(direction);
+                }
+                let mut beside = directions
+                    .iter()
+                    .filter(|(i, _)| {
+                        channels[**i] == channel
+                            && (store[**i].point.x - obj.point.x).abs() <= staff.spacing
+                    })
+                    .map(|(_, direction)| voice(direction));
+                match beside.next() {
+                    Some(taken) if beside.all(|other| other == taken) => 3 - taken,
+                    _ if obj.point.y < staff.y + staff.height() / 2 => 1,
+                    _ => 2,
+                }
+            })
+            .collect()
+    }
+
+    // Chord symbols are kept with the first voice of the staff, at the onset of the
+    // note they are set over in whichever voice
+    fn put_harmony(
+        collectors: &mut [Collector],
+        staff: usize,
+        voice: usize,
+        harmony: Option<&Harmony>,
+    ) {
+        if let Some(harmony) = harmony {
+            let onset = collectors[voice].onset();
+            collectors[staff].put_harmony(onset, harmony.clone());
+        }
+    }
+
+    // Collector of the voice on the staff, started when first heard
+    fn voice(collectors: &mut Vec<Collector>, staff: usize, voice: u8) -> usize {
+        if let Some(index) = collectors
+            .iter()
+            .position(|collector| collector.staff == staff && collector.voice == voice)
+        {
+            return index;
+        }
+        let follower = collectors[staff].follow(voice);
+        collectors.push(follower);
+        collectors.len() - 1
+    }
+
+    pub fn process(&mut self, collectors: &mut Vec<Collector>) -> Vec<Error> {
+        let mut warnings = vec![];
+        let staves = &self.staves;
+        let beams = &self.beams;
+        let brackets = &self.brackets;
+        let voltas = &self.voltas;
+        let scale = self.scale;
+        let styles = self.bars.iter().map(Bar::style).collect::<Vec<_>>();
+        let endings = self
+            .voltas
+            .iter()
+            .filter_map(|volta| self.ending(volta).map(|ending| (volta.clone(), ending)))
+            .collect::<Vec<_>>();
+        let ends = self
+            .bars
+            .iter()
+            .skip(1)
+            .map(|bar| bar.x)
+            .chain(self.staves.iter().map(|staff| staff.x2).max())
+            .collect::<Vec<_>>();
+        // Texts taken as tempo or repeat marks are no lyrics
+        let texts = self
+            .bars
+            .iter()
+            .flat_map(|bar| {
+                let (_, marked) = Mark::find(&bar.store, &bar.texts);
+                let (_, _, tempos) = Tempo::find(&bar.store, &bar.texts, scale);
+                bar.texts
+                    .iter()
+                    .enumerate()
+                    .filter(move |(index, _)| !marked.contains(index) && !tempos.contains(index))
+                    .map(|(_, text)| text)
+            })
+            .collect::<Vec<_>>();
+        let heads = self
+            .bars
+            .iter()
+            .flat_map(|bar| bar.store.iter())
+            .filter(|obj| matches!(obj.t, Type::Head(_)) && !obj.small)
+            .map(|obj| &obj.point)
+            .collect::<Vec<_>>();
+        let syllables = Lyric::find(&texts, staves, &self.extenders, &heads);
+        for (number, bar) in self.bars.iter_mut().enumerate() {
+            if bar.closing {
+                continue;
+            }
             bar.store.sort_by(|a, b| match a.point.x.cmp(&b.point.x) {
                 std::cmp::Ordering::Equal => b.point.y.cmp(&a.point.y),
                 etc => etc,
             });
             bar.stems.sort();
-            collectors[0].prepare();
-            collectors[1].prepare();
-            for obj in bar.store.iter() {
-                let channel = if obj.point.y >= self.y + self.height - self.scale * 5 {
-                    0
-                } else {
-                    1
-                };
-
+            let left = styles[number].left();
+            let right = styles
+                .get(number + 1)
+                .map_or(Barline::Single, |style| style.right());
+            let (marks, _) = Mark::find(&bar.store, &bar.texts);
+            let ending = endings
+                .iter()
+                .find(|(volta, _)| volta.x1 < ends[number] && bar.x + scale < volta.x2)
+                .map(|(_, ending)| *ending);
+            for collector in collectors.iter_mut() {
+                collector.prepare();
+                collector.put_barlines(left, right);
+                collector.put_marks(&marks, ending);
+            }
+            let channels = bar
+                .store
+                .iter()
+                .map(|obj| Staff::nearest(staves, obj.point.y, obj.point.y))
+                .collect::<Vec<_>>();
+            // Volta numbers count no tuplet
+            let mut signatures = bar
+                .store
+                .iter()
+                .enumerate()
+                .filter(|(_, obj)| matches!(obj.t, Type::Digit(_)))
+                .filter(|(_, obj)| {
+                    voltas
+                        .iter()
+                        .any(|volta| Self::numbers(volta, &obj.point, scale))
+                })
+                .map(|(index, _)| index)
+                .collect::<HashSet<_>>();
+            let mut tuplets = HashMap::new();
+            // Key changes come at the start of a system or after a double barline
+            let opening = number == 0 || matches!(styles[number], Barline::Double | Barline::Final);
+            for (channel, staff) in staves.iter().enumerate() {
+                let objects = bar
+                    .store
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| channels[*index] == channel)
+                    .collect::<Vec<_>>();
+                if let Some((key, indices)) =
+                    Key::find(&objects, collectors[channel].clef, staff, opening)
+                {
+                    for collector in collectors.iter_mut().filter(|c| c.staff == channel) {
+                        collector.put_key(key);
+                    }
+                    signatures.extend(indices);
+                }
+                if let Some((time, indices)) = TimeSignature::find(&objects, staff) {
+                    for collector in collectors.iter_mut().filter(|c| c.staff == channel) {
+                        collector.put_time(time);
+                    }
+                    signatures.extend(indices);
+                }
+                let objects = objects
+                    .into_iter()
+                    .filter(|(index, _)| !signatures.contains(index))
+                    .collect::<Vec<_>>();
+                for (tuplet, members) in Tuplet::find(&objects, staff, beams, &bar.stems, brackets)
+                {
+                    tuplets.extend(members.into_iter().map(|index| (index, tuplet)));
+                }
+            }
+            let (found, metronomes, _) = Tempo::find(&bar.store, &bar.texts, scale);
+            // The note of a metronome mark is not part of the music
+            signatures.extend(metronomes);
+            let tempos = Self::attach_tempos(&bar.store, &channels, &signatures, found, scale);
+            let lyrics = syllables
+                .iter()
+                .filter(|(_, point, _)| bar.x <= point.x && point.x < ends[number])
+                .collect::<Vec<_>>();
+            let lyrics = Self::attach_lyrics(&bar.store, &channels, &signatures, &lyrics, scale);
+            // A syllable spelled like a chord stays a syllable
+            let mut harmonies = Harmony::find(staves, &bar.texts);
+            harmonies.retain(|(_, point, _)| syllables.iter().all(|(_, at, _)| at != point));
+            let harmonies = Self::attach_harmonies(&bar.store, &channels, &signatures, harmonies);
+            let attached = Self::attach_accidentals(&bar.store, &channels, staves);
+            let (articulations, staccatos) =
+                Self::attach_articulations(&bar.store, &channels, staves);
+            let dots = Self::attach_dots(&bar.store, &channels, staves, &staccatos);
+            let (dynamics, wedges) =
+                Self::attach_dynamics(bar, ends[number], &channels, staves, &self.hairpins);
+            let (starts, ends) = Self::attach_arcs(&bar.store, &channels, staves, &self.arcs);
+            let ledgered = Self::attach_ledgers(&bar.store, &channels, staves, &self.ledgers);
+            let mut stems = HashMap::new();
+            for (index, obj) in bar.store.iter().enumerate() {
                 match obj.t {
-                    Type::Head(size) if size == 2 || size == 4 => {
-                        if self.head_size.is_none() {
-                            if let Some(head_size) = bar.stems.get_head_size(&obj) {
+                    Type::Head(size) if size >= 2 && !signatures.contains(&index) => {
+                        if self.head_size.is_none() && !obj.small {
+                            if let Some(head_size) = bar.stems.get_head_size(obj) {
                                 if head_size > self.scale {
                                     self.head_size = Some(head_size);
                                 }
                             }
                         }
-                        if !bar.stems.attach(obj, self.scale / 2, self.head_size) {
-                            panic!("Cannot attach {:?}, head_size: {:?}", obj, self.head_size);
+                        let width = match self.head_size {
+                            Some(width) if obj.small => Some(width * 0.75),
+                            etc => etc,
+                        };
+                        match bar.stems.attach(obj, self.scale / 2, width) {
+                            Some(stem) => {
+                                stems.insert(index, stem);
+                            }
+                            None => warnings.push(Error::Stemless {
+                                x: obj.point.x,
+                                y: obj.point.y,
+                            }),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            // Flags by the stem they hang on, taken before any head is written: the flag of
+            // a down stem sorts before its head. Further flags stacked on a stem halve it again
+            let mut flags: Vec<(VertLine, i32, Direction)> = vec![];
+            for obj in bar.store.iter() {
+                if let Type::Wing(len, direction) = obj.t {
+                    if let Some(stem) = bar.stems.attach_flag(obj, direction, self.scale / 2) {
+                        match flags.iter_mut().find(|(flagged, _, _)| *flagged == stem) {
+                            Some((_, len, _)) => *len /= 2,
+                            None => flags.push((stem, 1920 / i32::from(len), direction)),
                         }
+                    }
+                }
+            }
+            let voices = Self::separate(&bar.store, &channels, staves, &stems, beams);
+            let graces = Self::attach_graces(&bar.store, &channels, staves, &stems, &self.slashes);
+            // A head with all that is attached to it, stemmed or not
+            let put_head = |collector: &mut Collector,
+                            index: usize,
+                            point: &Point,
+                            len: i32,
+                            stem: Option<(Fixed, Direction)>| {
+                collector.put_head(&staves[channels[index]], point, len, stem);
+                collector.put_dots(dots.get(&index).cloned().unwrap_or(0));
+                collector.put_tuplet(tuplets.get(&index).cloned());
+                collector.put_arcs(
+                    starts.get(&index).map_or(&[], Vec::as_slice),
+                    ends.get(&index).map_or(&[], Vec::as_slice),
+                );
+                collector.put_dynamics(
+                    dynamics.get(&index).cloned(),
+                    wedges.get(&index).map_or(&[], Vec::as_slice),
+                );
+                collector.put_articulations(articulations.get(&index).map_or(&[], Vec::as_slice));
+                collector.put_small(graces.get(&index).cloned());
+                collector.put_tempo(tempos.get(&index).cloned());
+                collector.put_lyrics(lyrics.get(&index).map_or(&[], Vec::as_slice));
+            };
+            let mut multirest = 0;
+            for (index, obj) in bar.store.iter().enumerate() {
+                if signatures.contains(&index) {
+                    continue;
+                }
+                let channel = channels[index];
+                let staff = &staves[channel];
+                let point = match ledgered.get(&index) {
+                    Some(y) => Point::new(obj.point.x, *y),
+                    None => obj.point.clone(),
+                };
 
-                        collectors[channel].put_quarter(
-                            // TODO: quarter or half
-                            obj.point.x,
-                            (borders[channel] - obj.point.y) / self.scale,
+                match obj.t {
+                    Type::Head(size) if stems.contains_key(&index) => {
+                        let stem = &stems[&index];
+                        let flag = flags.iter().find(|(flagged, _, _)| flagged == stem);
+                        // Black heads are quarters until beams or flags say otherwise
+                        let len = match flag {
+                            Some((_, len, _)) if size == 4 => *len,
+                            _ if size == 4 => {
+                                let beamed = beams.iter().filter(|beam| beam.touches(stem)).count();
+                                480 >> beamed.min(4)
+                            }
+                            _ => 1920 / i32::from(size),
+                        };
+
+                        let direction = flag.map_or_else(
+                            || Stems::direction(stem, obj),
+                            |(_, _, direction)| *direction,
                         );
-                    }
-                    Type::Wing(8) => {
-                        collectors[channel].put_wing(obj.point.x);
+                        let voice = Self::voice(collectors, channel, voices[index]);
+                        put_head(
+                            &mut collectors[voice],
+                            index,
+                            &point,
+                            len,
+                            Some((stem.x, direction)),
+                        );
+                        Self::put_harmony(collectors, channel, voice, harmonies.get(&index));
                     }
                     Type::Rest(len) => {
-                        collectors[channel].put_rest(obj.point.x, len);
+                        let voice = Self::voice(collectors, channel, voices[index]);
+                        collectors[voice].put_rest(obj.point.x, len);
+                        Self::put_harmony(collectors, channel, voice, harmonies.get(&index));
+                        collectors[voice].put_dots(dots.get(&index).cloned().unwrap_or(0));
+                        collectors[voice].put_tuplet(tuplets.get(&index).cloned());
+                        collectors[voice].put_articulations(
+                            articulations.get(&index).map_or(&[], Vec::as_slice),
+                        );
+                    }
+                    Type::Multirest => {
+                        let voice = Self::voice(collectors, channel, 1);
+                        collectors[voice].put_measure_rest();
+                        Self::put_harmony(collectors, channel, voice, harmonies.get(&index));
+                        multirest = multirest.max(Self::count(&bar.store, obj, staff));
                     }
-                    Type::Head(1) => {
-                        collectors[channel]
-                            .put_whole(obj.point.x, (borders[channel] - obj.point.y) / self.scale);
+                    Type::Head(size) => {
+                        // Whole notes and breves (0) have no stem, others may have lost theirs
+                        let len = if size == 0 {
+                            3840
+                        } else {
+                            1920 / i32::from(size)
+                        };
+                        let voice = Self::voice(collectors, channel, voices[index]);
+                        put_head(&mut collectors[voice], index, &point, len, None);
+                        Self::put_harmony(collectors, channel, voice, harmonies.get(&index));
+                    }
+                    Type::Clef(sign, octave) => {
+                        let clef = Clef::locate(sign, octave, staff, obj.point.y);
+                        for collector in collectors.iter_mut().filter(|c| c.staff == channel) {
+                            collector.put_clef(clef);
+                        }
+                    }
+                    Type::Accidental(accidental) if attached.contains(&index) => {
+                        for collector in collectors.iter_mut().filter(|c| c.staff == channel) {
+                            collector.put_accidental(staff, obj.point.y, accidental);
+                        }
                     }
                     _ => {
                         // println!("{:?}", obj);
                     }
                 }
             }
-            bar.debug();
-            /*
-            i += 1;
-            println!("{}", i);
-            if i == 4 {
-                let mut smf = crate::smf::Smf::new(152);
-                smf.write(&collectors);
-                panic!("HELLO");
+            for collector in collectors.iter_mut() {
+                collector.clear();
+                collector.complete();
+                if let Some((found, expected)) = collector.check() {
+                    warnings.push(Error::Duration {
+                        x: bar.x,
+                        y: staves[collector.staff].y,
+                        found,
+                        expected,
+                    });
+                }
+            }
+            // Voices falling silent before the end of the bar still take its time
+            let longest = collectors.iter().map(Collector::measure_len).max();
+            for collector in collectors.iter_mut() {
+                let gap = longest.unwrap_or(0) - collector.measure_len();
+                if gap > 0 {
+                    collector.put_space(gap);
+                }
+            }
+            // The bars a multi-measure rest stands for after its own
+            for _ in 1..multirest {
+                for collector in collectors.iter_mut() {
+                    collector.prepare();
+                    if collector.voice == 1 {
+                        collector.put_measure_rest();
+                    } else {
+                        collector.put_space(collector.duration());
+                    }
+                }
             }
-            */
         }
+        for collector in collectors.iter_mut() {
+            collector.break_system();
+        }
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recognizer::{Note, Pitch};
+
+    // One five-line staff a space of 10 apart, barlines at the given x and at its end
+    fn stanza(bars: &[i64]) -> Stanza {
+        let staff = Staff::new(
+            Fixed::new(0),
+            Fixed::new(500),
+            Fixed::new(100),
+            5,
+            Fixed::new(10),
+        );
+        let mut stanza = Stanza::new(vec![staff]);
+        for x in bars.iter().chain([500].iter()) {
+            assert!(stanza.insert_bar(&VertLine::new(*x, 100, 140), Fixed::new(1)));
+        }
+        stanza.sort_bars();
+        stanza
+    }
+
+    fn put(stanza: &mut Stanza, t: Type, x: i64, y: i64) {
+        assert!(stanza.put_object(&Object::new(t, Point::new(x, y))));
+    }
+
+    // Head with a stem going up from it on its right
+    fn put_up(stanza: &mut Stanza, size: u8, x: i64, y: i64) {
+        put(stanza, Type::Head(size), x, y);
+        assert!(stanza.put_stem(&VertLine::new(x + 6, y - 35, y)));
+    }
+
+    fn process(stanza: &mut Stanza) -> (Vec<Collector>, Vec<Error>) {
+        let mut collectors = vec![Collector::new(0)];
+        let warnings = stanza.process(&mut collectors);
+        (collectors, warnings)
+    }
+
+    fn lens(collector: &Collector) -> Vec<i32> {
+        collector.notes.iter().map(Note::len).collect()
+    }
+
+    #[test]
+    fn lengths_from_head_type() {
+        let mut stanza = stanza(&[]);
+        put(&mut stanza, Type::Head(0), 20, 120);
+        put(&mut stanza, Type::Head(1), 80, 120);
+        put_up(&mut stanza, 2, 140, 120);
+        put_up(&mut stanza, 4, 200, 120);
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(lens(&collectors[0]), vec![3840, 1920, 960, 480]);
+    }
+
+    #[test]
+    fn dots_beside_notes_and_rests() {
+        let mut stanza = stanza(&[]);
+        put_up(&mut stanza, 2, 20, 120);
+        put(&mut stanza, Type::Dot, 35, 115);
+        put_up(&mut stanza, 4, 80, 130);
+        put(&mut stanza, Type::Dot, 95, 125);
+        put(&mut stanza, Type::Dot, 101, 125);
+        put(&mut stanza, Type::Rest(8), 140, 120);
+        put(&mut stanza, Type::Dot, 155, 115);
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(lens(&collectors[0]), vec![1440, 840, 360]);
+    }
+
+    #[test]
+    fn heads_on_a_stem_make_a_chord() {
+        let mut stanza = stanza(&[]);
+        put(&mut stanza, Type::Head(4), 20, 130);
+        put(&mut stanza, Type::Head(4), 20, 120);
+        assert!(stanza.put_stem(&VertLine::new(26, 85, 130)));
+        // A second on the same stem sits right of it
+        put(&mut stanza, Type::Head(4), 80, 130);
+        put(&mut stanza, Type::Head(4), 90, 125);
+        assert!(stanza.put_stem(&VertLine::new(86, 90, 130)));
+        let (collectors, _) = process(&mut stanza);
+        let sizes = collectors[0]
+            .notes
+            .iter()
+            .filter_map(|note| match note {
+                Note::Chord(chord) => Some(chord.tones.len()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![2, 2]);
+    }
+
+    #[test]
+    fn fermatas_over_notes_and_rests() {
+        let mut stanza = stanza(&[]);
+        put_up(&mut stanza, 2, 20, 120);
+        put(
+            &mut stanza,
+            Type::Articulation(Articulation::Fermata),
+            23,
+            70,
+        );
+        put(&mut stanza, Type::Rest(2), 140, 120);
+        put(
+            &mut stanza,
+            Type::Articulation(Articulation::Fermata),
+            140,
+            85,
+        );
+        // Other marks are left to heads
+        put(&mut stanza, Type::Rest(2), 260, 120);
+        put(
+            &mut stanza,
+            Type::Articulation(Articulation::Accent),
+            260,
+            100,
+        );
+        let (collectors, _) = process(&mut stanza);
+        let fermatas = collectors[0]
+            .notes
+            .iter()
+            .map(|note| match note {
+                Note::Chord(chord) => chord.articulations == [Articulation::Fermata],
+                Note::Rest(rest) => rest.fermata,
+                Note::Space(_) => false,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(fermatas, vec![true, true, false]);
+    }
+
+    fn small(t: Type, x: i64, y: i64) -> Object {
+        let mut obj = Object::new(t, Point::new(x, y));
+        obj.small = true;
+        obj
+    }
+
+    #[test]
+    fn volta_numbers_are_no_tuplets() {
+        let mut stanza = stanza(&[]);
+        put_up(&mut stanza, 2, 20, 120);
+        put_up(&mut stanza, 2, 60, 120);
+        assert!(stanza.put_object(&small(Type::Digit(2), 17, 82)));
+        assert!(stanza.put_volta(&HorzLine::new(15, 200, 75, 1)));
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(lens(&collectors[0]), vec![960, 960]);
+    }
+
+    #[test]
+    fn graces_lead_into_a_note() {
+        let staves = [Staff::new(
+            Fixed::new(0),
+            Fixed::new(500),
+            Fixed::new(100),
+            5,
+            Fixed::new(10),
+        )];
+        let store = vec![
+            small(Type::Head(8), 20, 120),
+            small(Type::Head(8), 35, 115),
+            Object::new(Type::Head(4), Point::new(50, 120)),
+            // Small notes running into a rest or nothing are cues
+            small(Type::Head(4), 200, 120),
+            Object::new(Type::Rest(4), Point::new(220, 120)),
+            small(Type::Head(4), 400, 120),
+        ];
+        let mut stems = HashMap::new();
+        stems.insert(0, VertLine::new(25, 95, 120));
+        stems.insert(1, VertLine::new(40, 90, 115));
+        let slashes = [Line::new(18, 110, 30, 100)];
+        let graces = Stanza::attach_graces(&store, &[0; 6], &staves, &stems, &slashes);
+        assert_eq!(graces[&0], Some(Grace::Acciaccatura));
+        assert_eq!(graces[&1], Some(Grace::Appoggiatura));
+        assert_eq!(graces[&3], None);
+        assert_eq!(graces[&5], None);
+        assert!(!graces.contains_key(&2));
+    }
+
+    #[test]
+    fn tempo_words_are_no_lyrics() {
+        let mut stanza = stanza(&[]);
+        for x in [20, 140, 260, 380].iter() {
+            put_up(&mut stanza, 4, *x, 120);
+        }
+        for (text, x) in [("la", 17), ("la", 137), ("la", 257), ("rit.", 377)].iter() {
+            let text = Text::new(text.to_string(), Point::new(*x, 180));
+            assert!(stanza.put_text(&text));
+        }
+        let (collectors, _) = process(&mut stanza);
+        let sung = collectors[0]
+            .notes
+            .iter()
+            .map(|note| match note {
+                Note::Chord(chord) => chord.lyrics.len(),
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sung, vec![1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn metronome_mark_high_above() {
+        let mut stanza = stanza(&[]);
+        put_up(&mut stanza, 4, 20, 120);
+        put_up(&mut stanza, 4, 140, 120);
+        // Six spaces over the staff, glyph and number alike
+        put(&mut stanza, Type::Head(4), 20, 40);
+        assert!(stanza.put_text(&Text::new("= 96".to_string(), Point::new(32, 42))));
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(lens(&collectors[0]), vec![480, 480]);
+        let tempo = match &collectors[0].notes[0] {
+            Note::Chord(chord) => chord.tempo.clone(),
+            _ => None,
+        };
+        assert_eq!(tempo.map(|tempo| tempo.beat), Some(Some((480, 96))));
+    }
+
+    #[test]
+    fn flags_on_up_and_down_stems() {
+        let mut stanza = stanza(&[]);
+        // The flag of a down stem hangs below its head and sorts before it
+        put_down(&mut stanza, 4, 20, 120);
+        put(&mut stanza, Type::Wing(8, Direction::Down), 20, 155);
+        // Two flag segments stacked on one stem
+        put_up(&mut stanza, 4, 140, 120);
+        put(&mut stanza, Type::Wing(16, Direction::Up), 146, 85);
+        put(&mut stanza, Type::Wing(16, Direction::Up), 146, 89);
+        put_up(&mut stanza, 4, 260, 120);
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(lens(&collectors[0]), vec![240, 60, 480]);
+        let stems = collectors[0]
+            .notes
+            .iter()
+            .filter_map(|note| match note {
+                Note::Chord(chord) => chord.stem,
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(stems, vec![Direction::Down, Direction::Up, Direction::Up]);
+    }
+
+    #[test]
+    fn stemless_head_is_a_warning() {
+        let mut stanza = stanza(&[]);
+        put(&mut stanza, Type::Head(4), 20, 120);
+        let (collectors, warnings) = process(&mut stanza);
+        assert!(warnings
+            .iter()
+            .any(|warning| matches!(warning, Error::Stemless { .. })));
+        assert_eq!(lens(&collectors[0]), vec![480]);
+    }
+
+    // Head with a stem going down from it on its left
+    fn put_down(stanza: &mut Stanza, size: u8, x: i64, y: i64) {
+        put(stanza, Type::Head(size), x, y);
+        assert!(stanza.put_stem(&VertLine::new(x, y, y + 35)));
+    }
+
+    fn voices(collectors: &[Collector]) -> Vec<(u8, Vec<i32>)> {
+        collectors
+            .iter()
+            .map(|collector| (collector.voice, lens(collector)))
+            .collect()
+    }
+
+    #[test]
+    fn voices_by_stem_direction() {
+        let mut stanza = stanza(&[]);
+        for x in [20, 80, 140, 200].iter() {
+            put_up(&mut stanza, 4, *x, 115);
+        }
+        put_down(&mut stanza, 2, 20, 135);
+        let (collectors, _) = process(&mut stanza);
+        // The lower voice is padded to the end of the bar
+        assert_eq!(
+            voices(&collectors),
+            vec![(1, vec![480, 480, 480, 480]), (2, vec![960, 960])]
+        );
+        assert!(matches!(collectors[1].notes[1], Note::Space(960)));
+    }
+
+    #[test]
+    fn beam_keeps_its_stems_together() {
+        let mut stanza = stanza(&[]);
+        put_up(&mut stanza, 4, 20, 130);
+        put_down(&mut stanza, 4, 36, 105);
+        assert!(stanza.put_beam(&Quadrangle {
+            points: [
+                Point::new(26, 110),
+                Point::new(36, 110),
+                Point::new(36, 114),
+                Point::new(26, 114),
+            ],
+        }));
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(voices(&collectors), vec![(1, vec![240, 240])]);
+    }
+
+    #[test]
+    fn rest_beside_a_note_takes_the_other_voice() {
+        let mut stanza = stanza(&[]);
+        put_up(&mut stanza, 4, 20, 115);
+        put(&mut stanza, Type::Rest(4), 21, 110);
+        put_up(&mut stanza, 4, 80, 115);
+        put_down(&mut stanza, 4, 80, 135);
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(
+            voices(&collectors),
+            vec![(1, vec![480, 480]), (2, vec![480, 480])]
+        );
+        assert!(matches!(collectors[1].notes[0], Note::Rest(_)));
+    }
+
+    fn pitches(collector: &Collector) -> Vec<Pitch> {
+        collector
+            .notes
+            .iter()
+            .filter_map(|note| match note {
+                Note::Chord(chord) => Some(chord.tones[0]),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn heads_on_ledger_lines() {
+        let mut stanza = stanza(&[]);
+        assert!(stanza.put_ledger(&HorzLine::new(16, 30, 150, 1)));
+        assert!(stanza.put_ledger(&HorzLine::new(76, 90, 90, 1)));
+        assert!(stanza.put_ledger(&HorzLine::new(76, 90, 80, 1)));
+        // Too far from the staff, or too long
+        assert!(!stanza.put_ledger(&HorzLine::new(76, 90, 10, 1)));
+        assert!(!stanza.put_ledger(&HorzLine::new(76, 190, 160, 1)));
+        put_up(&mut stanza, 4, 20, 150);
+        put_down(&mut stanza, 4, 80, 80);
+        put_down(&mut stanza, 4, 140, 85);
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(
+            pitches(&collectors[0]),
+            vec![Pitch::new(0, 0), Pitch::new(14, 0), Pitch::new(13, 0)]
+        );
+    }
+
+    fn block(x1: i64, y1: i64, x2: i64, y2: i64) -> Quadrangle {
+        Quadrangle {
+            points: [
+                Point::new(x1, y1),
+                Point::new(x2, y1),
+                Point::new(x2, y2),
+                Point::new(x1, y2),
+            ],
+        }
+    }
+
+    #[test]
+    fn whole_rest_glyph_hangs_from_the_fourth_line() {
+        let mut stanza = stanza(&[]);
+        put(&mut stanza, Type::Rest(1), 20, 110);
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(lens(&collectors[0]), vec![1920]);
+    }
+
+    #[test]
+    fn half_rest_glyph_sits_on_the_middle_line() {
+        let mut stanza = stanza(&[]);
+        put(&mut stanza, Type::Rest(1), 20, 120);
+        put(&mut stanza, Type::Rest(1), 80, 120);
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(lens(&collectors[0]), vec![960, 960]);
+    }
+
+    #[test]
+    fn rests_from_blocks() {
+        let mut stanza = stanza(&[]);
+        // Hanging from the fourth line, sitting on the middle one
+        assert!(stanza.put_block(&block(20, 110, 32, 115)));
+        assert!(stanza.put_block(&block(80, 115, 92, 120)));
+        // Off the lines, or slanted
+        assert!(!stanza.put_block(&block(140, 112, 152, 117)));
+        assert!(!stanza.put_block(&Quadrangle {
+            points: [
+                Point::new(140, 110),
+                Point::new(152, 108),
+                Point::new(152, 113),
+                Point::new(140, 115),
+            ],
+        }));
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(lens(&collectors[0]), vec![1920, 960]);
+    }
+
+    #[test]
+    fn multirest_with_its_count() {
+        let mut stanza = stanza(&[]);
+        assert!(stanza.put_block(&block(100, 116, 160, 124)));
+        put(&mut stanza, Type::Digit(1), 124, 90);
+        put(&mut stanza, Type::Digit(2), 130, 90);
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(collectors[0].measures.len(), 12);
+        assert!(collectors[0].notes.iter().all(|note| match note {
+            Note::Rest(rest) => rest.measure && rest.len == 1920,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn beam_at_the_middle_line() {
+        let mut stanza = stanza(&[]);
+        put(&mut stanza, Type::Head(4), 20, 130);
+        assert!(stanza.put_stem(&VertLine::new(26, 117, 130)));
+        put(&mut stanza, Type::Head(4), 60, 130);
+        assert!(stanza.put_stem(&VertLine::new(66, 117, 130)));
+        let beam = block(26, 117, 66, 122);
+        assert!(!stanza.put_block(&beam));
+        assert!(stanza.put_beam(&beam));
+        // A beamlet on a line
+        put(&mut stanza, Type::Head(4), 100, 140);
+        assert!(stanza.put_stem(&VertLine::new(106, 110, 140)));
+        assert!(!stanza.put_block(&block(96, 110, 106, 114)));
+        let (collectors, _) = process(&mut stanza);
+        assert_eq!(lens(&collectors[0]), vec![240, 240, 480]);
+    }
+
+    #[test]
+    fn repeat_dots_in_pairs() {
+        let mut stanza = stanza(&[246]);
+        assert!(stanza.insert_bar(&VertLine::new(250, 100, 140), Fixed::new(4)));
+        stanza.sort_bars();
+        let dot = |x: i64, y: i64| Object::new(Type::Dot, Point::new(x, y));
+        // The dot of a dotted B4 right before the barline
+        let single = [dot(240, 115)];
+        assert!(!stanza.put_repeat_dot(&single[0], &single));
+        assert_eq!(stanza.bars[1].style(), Barline::Final);
+        let pair = [dot(240, 115), dot(240, 125)];
+        assert!(stanza.put_repeat_dot(&pair[0], &pair));
+        assert!(stanza.put_repeat_dot(&pair[1], &pair));
+        assert_eq!(stanza.bars[1].style(), Barline::EndRepeat);
     }
 }
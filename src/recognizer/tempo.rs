@@ -0,0 +1,220 @@
+use crate::common::{Fixed, Object, Point, Text, Type};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    // Quarter notes per minute
+    Set(u32),
+    Ritardando,
+    Accelerando,
+    // Back to the last tempo set, after a ritardando or accelerando
+    Resume,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tempo {
+    // Words as printed, empty for a bare metronome mark
+    pub words: String,
+    pub change: Change,
+    // Ticks of the note a metronome number counts and the number, as printed
+    pub beat: Option<(i32, u32)>,
+}
+
+impl Tempo {
+    // Usual speeds of the Italian terms
+    fn speed(word: &str) -> Option<u32> {
+        let speed = match word {
+            "grave" => 40,
+            "largo" => 50,
+            "lento" => 56,
+            "larghetto" => 63,
+            "adagio" => 70,
+            "andante" => 92,
+            "andantino" => 96,
+            "moderato" => 112,
+            "allegretto" => 116,
+            "allegro" => 132,
+            "vivace" => 160,
+            "presto" => 180,
+            "prestissimo" => 200,
+            _ => return None,
+        };
+        Some(speed)
+    }
+
+    // Words with an optional "= n" at the end, n in beats of `unit` ticks
+    fn parse(text: &str, unit: i32) -> Option<Tempo> {
+        let (words, number) = match text.find('=') {
+            Some(split) => (&text[..split], Some(&text[split + 1..])),
+            None => (text, None),
+        };
+        let words = words.trim().trim_end_matches('(').trim();
+        let number = number.and_then(|number| {
+            let digits = number
+                .trim()
+                .chars()
+                .take_while(char::is_ascii_digit)
+                .collect::<String>();
+            digits.parse::<u32>().ok()
+        });
+        let lower = words.to_lowercase();
+        let first = lower
+            .split(|c: char| !c.is_alphabetic())
+            .next()
+            .unwrap_or("");
+        let beat = number.map(|number| (unit, number));
+        let change = if let Some(number) = number {
+            Change::Set(number * unit as u32 / 480)
+        } else if let Some(speed) = Self::speed(first) {
+            Change::Set(speed)
+        } else if ["rit", "ritard", "ritardando", "rall", "rallentando"].contains(&first) {
+            Change::Ritardando
+        } else if ["accel", "accelerando"].contains(&first) {
+            Change::Accelerando
+        } else if lower.starts_with("a tempo") || lower.starts_with("tempo i") {
+            Change::Resume
+        } else {
+            return None;
+        };
+        Some(Tempo {
+            words: words.to_string(),
+            change,
+            beat,
+        })
+    }
+
+    // Length of the note drawn before the "=" of a metronome mark, quarter by default
+    fn unit(objects: &[Object], text: &Text, scale: Fixed) -> (i32, Option<usize>) {
+        let head = objects
+            .iter()
+            .enumerate()
+            .filter(|(_, obj)| {
+                matches!(obj.t, Type::Head(_))
+                    && obj.point.x < text.point.x
+                    && text.point.x - obj.point.x <= scale * 4
+                    && (text.point.y - obj.point.y).abs() <= scale * 2
+            })
+            .max_by_key(|(_, obj)| obj.point.x);
+        let (index, obj) = match head {
+            Some(head) => head,
+            None => return (480, None),
+        };
+        let len = match obj.t {
+            Type::Head(size) if size >= 2 => 1920 / i32::from(size),
+            _ => 1920,
+        };
+        let dotted = objects.iter().any(|dot| {
+            matches!(dot.t, Type::Dot)
+                && obj.point.x < dot.point.x
+                && dot.point.x < text.point.x
+                && (dot.point.y - obj.point.y).abs() <= scale
+        });
+        (if dotted { len * 3 / 2 } else { len }, Some(index))
+    }
+
+    // Tempo marks in the bar with where they stand, the note glyphs of metronome
+    // marks which are not to be played, and the texts taken as tempo marks
+    pub fn find(
+        objects: &[Object],
+        texts: &[Text],
+        scale: Fixed,
+    ) -> (Vec<(Point, Tempo)>, Vec<usize>, Vec<usize>) {
+        // "=" and the number may be set as runs of their own
+        let mut joined: Vec<(Text, Vec<usize>)> = vec![];
+        for (index, text) in texts.iter().enumerate() {
+            match joined.last_mut() {
+                Some((last, runs))
+                    if last.text.trim_end().ends_with('=')
+                        && (text.point.y - last.point.y).abs() <= scale
+                        && text.point.x > last.point.x
+                        && text.point.x - last.point.x <= scale * 4 =>
+                {
+                    last.text.push_str(&text.text);
+                    runs.push(index);
+                }
+                _ => joined.push((text.clone(), vec![index])),
+            }
+        }
+        let mut tempos = vec![];
+        let mut glyphs = vec![];
+        let mut claimed = vec![];
+        for (text, runs) in joined.iter() {
+            let (unit, glyph) = if text.text.contains('=') {
+                Self::unit(objects, text, scale)
+            } else {
+                (480, None)
+            };
+            if let Some(tempo) = Self::parse(&text.text, unit) {
+                // A bare mark stands where its note is drawn
+                let point = match glyph {
+                    Some(glyph) if tempo.words.is_empty() => objects[glyph].point.clone(),
+                    _ => text.point.clone(),
+                };
+                tempos.push((point, tempo));
+                glyphs.extend(glyph);
+                claimed.extend_from_slice(runs);
+            }
+        }
+        (tempos, glyphs, claimed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str, x: i64, y: i64) -> Text {
+        Text::new(text.to_string(), Point::new(x, y))
+    }
+
+    #[test]
+    fn words_and_metronome_numbers() {
+        let parse = |text| Tempo::parse(text, 480).map(|tempo| tempo.change);
+        assert_eq!(parse("Allegro"), Some(Change::Set(132)));
+        assert_eq!(parse("Andante con moto"), Some(Change::Set(92)));
+        assert_eq!(parse("Allegro (= 120)"), Some(Change::Set(120)));
+        assert_eq!(parse("= 72"), Some(Change::Set(72)));
+        assert_eq!(parse("rit."), Some(Change::Ritardando));
+        assert_eq!(parse("accel."), Some(Change::Accelerando));
+        assert_eq!(parse("a tempo"), Some(Change::Resume));
+        assert_eq!(parse("Tempo I"), Some(Change::Resume));
+        assert_eq!(parse("dolce"), None);
+        assert_eq!(Tempo::parse("Allegro (", 480).unwrap().words, "Allegro");
+    }
+
+    #[test]
+    fn numbers_count_in_the_drawn_note() {
+        assert_eq!(Tempo::parse("= 60", 960).unwrap().change, Change::Set(120));
+        assert_eq!(Tempo::parse("= 60", 720).unwrap().change, Change::Set(90));
+    }
+
+    #[test]
+    fn metronome_mark_with_its_note() {
+        let scale = Fixed::new(10);
+        let objects = vec![
+            Object::new(Type::Head(2), Point::new(100, 50)),
+            Object::new(Type::Dot, Point::new(112, 50)),
+            Object::new(Type::Head(4), Point::new(100, 120)),
+        ];
+        // "=" and the number set apart
+        let texts = vec![text("=", 120, 52), text("40", 130, 52)];
+        let (tempos, glyphs, claimed) = Tempo::find(&objects, &texts, scale);
+        assert_eq!(glyphs, vec![0]);
+        assert_eq!(claimed, vec![0, 1]);
+        assert_eq!(tempos.len(), 1);
+        let (point, tempo) = &tempos[0];
+        assert_eq!(*point, Point::new(100, 50));
+        assert_eq!(tempo.change, Change::Set(120));
+        assert_eq!(tempo.beat, Some((1440, 40)));
+    }
+
+    #[test]
+    fn words_alone_claim_no_notes() {
+        let objects = vec![Object::new(Type::Head(4), Point::new(100, 50))];
+        let texts = vec![text("Presto", 120, 50), text("cresc.", 300, 150)];
+        let (tempos, glyphs, claimed) = Tempo::find(&objects, &texts, Fixed::new(10));
+        assert!(glyphs.is_empty());
+        assert_eq!(claimed, vec![0]);
+        assert_eq!(tempos.len(), 1);
+        assert_eq!(tempos[0].1.change, Change::Set(180));
+    }
+}
@@ -0,0 +1,131 @@
+use crate::common::{Object, Type};
+use crate::recognizer::Staff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    pub beats: u8,
+    pub beat_type: u8,
+}
+
+impl TimeSignature {
+    pub fn new(beats: u8, beat_type: u8) -> Self {
+        Self { beats, beat_type }
+    }
+
+    // Length of a full measure in ticks
+    pub fn duration(&self) -> i32 {
+        1920 * i32::from(self.beats) / i32::from(self.beat_type)
+    }
+
+    fn number(digits: &[&&Object]) -> Option<u8> {
+        let mut digits = digits.to_vec();
+        digits.sort_by_key(|obj| obj.point.x);
+        digits.iter().try_fold(0u8, |acc, obj| match obj.t {
+            Type::Digit(d) => acc.checked_mul(10)?.checked_add(d),
+            _ => None,
+        })
+    }
+
+    // Time signatures follow the clef and key signature, with the digits inside the staff,
+    // returned with the indices of the objects they are made of
+    pub fn find(
+        objects: &[(usize, &Object)],
+        staff: &Staff,
+    ) -> Option<(TimeSignature, Vec<usize>)> {
+        let mut glyphs = vec![];
+        let mut indices = vec![];
+        for (index, obj) in objects.iter() {
+            match obj.t {
+                Type::Clef(..) | Type::Accidental(_) | Type::Parenthesis if glyphs.is_empty() => {}
+                Type::Time(beats, beat_type) if glyphs.is_empty() => {
+                    return Some((TimeSignature::new(beats, beat_type), vec![*index]));
+                }
+                Type::Digit(_)
+                    if staff.y - staff.spacing / 2 <= obj.point.y
+                        && obj.point.y <= staff.bottom() + staff.spacing / 2 =>
+                {
+                    glyphs.push(obj);
+                    indices.push(*index);
+                }
+                _ => break,
+            }
+        }
+        let middle = staff.y + staff.height() / 2;
+        let (upper, lower): (Vec<_>, Vec<_>) = glyphs.iter().partition(|obj| obj.point.y <= middle);
+        match (Self::number(&upper)?, Self::number(&lower)?) {
+            (0, _) | (_, 0) => None,
+            (beats, beat_type) => Some((TimeSignature::new(beats, beat_type), indices)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Accidental, Fixed, Point, Sign};
+
+    fn staff() -> Staff {
+        Staff::new(
+            Fixed::new(0),
+            Fixed::new(500),
+            Fixed::new(100),
+            5,
+            Fixed::new(10),
+        )
+    }
+
+    fn object(t: Type, x: i64, y: i64) -> Object {
+        Object::new(t, Point::new(x, y))
+    }
+
+    fn find(objects: &[Object]) -> Option<(TimeSignature, Vec<usize>)> {
+        let objects = objects.iter().enumerate().collect::<Vec<_>>();
+        TimeSignature::find(&objects, &staff())
+    }
+
+    #[test]
+    fn digits_after_clef_and_key() {
+        let objects = [
+            object(Type::Clef(Sign::G, 0), 5, 130),
+            object(Type::Accidental(Accidental::Sharp), 20, 100),
+            object(Type::Digit(6), 40, 110),
+            object(Type::Digit(8), 40, 130),
+            object(Type::Head(4), 60, 120),
+        ];
+        assert_eq!(find(&objects), Some((TimeSignature::new(6, 8), vec![2, 3])));
+    }
+
+    #[test]
+    fn numbers_of_two_digits() {
+        let objects = [
+            object(Type::Digit(2), 40, 110),
+            object(Type::Digit(1), 34, 110),
+            object(Type::Digit(1), 34, 130),
+            object(Type::Digit(6), 40, 130),
+        ];
+        assert_eq!(find(&objects).unwrap().0, TimeSignature::new(12, 16));
+    }
+
+    #[test]
+    fn symbols_and_duration() {
+        let objects = [object(Type::Time(2, 2), 40, 120)];
+        let (time, indices) = find(&objects).unwrap();
+        assert_eq!((time, indices), (TimeSignature::new(2, 2), vec![0]));
+        assert_eq!(time.duration(), 1920);
+        assert_eq!(TimeSignature::new(3, 4).duration(), 1440);
+        assert_eq!(TimeSignature::new(6, 8).duration(), 1440);
+    }
+
+    #[test]
+    fn digits_elsewhere_are_not_a_signature() {
+        // A tuplet number over the staff
+        let objects = [object(Type::Digit(3), 40, 80)];
+        assert_eq!(find(&objects), None);
+        let objects = [
+            object(Type::Head(4), 20, 120),
+            object(Type::Digit(3), 40, 110),
+            object(Type::Digit(4), 40, 130),
+        ];
+        assert_eq!(find(&objects), None);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::common::{Fixed, HorzLine, Object, Type};
+use crate::recognizer::bar::Stems;
+use crate::recognizer::{Beam, Staff};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuplet {
+    pub actual: u8,
+    pub normal: u8,
+}
+
+impl Tuplet {
+    // Duplets and quadruplets stand for three notes, octuplets for six, others for the next lower power of two
+    pub fn new(actual: u8) -> Self {
+        let normal = match actual {
+            2 | 4 => 3,
+            8 => 6,
+            _ => 1 << (7 - actual.leading_zeros()),
+        };
+        Self { actual, normal }
+    }
+
+    pub fn scale(&self, len: i32) -> i32 {
+        len * i32::from(self.normal) / i32::from(self.actual)
+    }
+
+    // Small digits above or below the staff number the notes under the beam or bracket
+    // they sit by, or, without one, as many notes centred on them whose stems end by the
+    // digit. A digit right by a head is a fingering
+    pub fn find(
+        objects: &[(usize, &Object)],
+        staff: &Staff,
+        beams: &[Beam],
+        stems: &Stems,
+        brackets: &[HorzLine],
+    ) -> Vec<(Tuplet, Vec<usize>)> {
+        let notes = objects
+            .iter()
+            .filter(|(_, obj)| matches!(obj.t, Type::Head(_) | Type::Rest(_)))
+            .collect::<Vec<_>>();
+        let mut res = vec![];
+        for (_, digit) in objects.iter() {
+            let actual = match digit.t {
+                Type::Digit(actual) if actual >= 2 && digit.small => actual,
+                _ => continue,
+            };
+            let (x, y) = (digit.point.x, digit.point.y);
+            if staff.y - staff.spacing / 2 <= y && y <= staff.bottom() + staff.spacing / 2 {
+                continue;
+            }
+            let fingering = notes.iter().any(|(_, obj)| {
+                matches!(obj.t, Type::Head(_))
+                    && (obj.point.x - x).abs() <= staff.spacing
+                    && (obj.point.y - y).abs() <= staff.spacing * 3
+            });
+            if fingering {
+                continue;
+            }
+            let reach = staff.spacing * 2;
+            let beam = beams
+                .iter()
+                .filter(|beam| beam.x1 <= x && x <= beam.x2)
+                .map(|beam| ((beam.y_at(x) - y).abs(), beam))
+                .filter(|(distance, _)| *distance <= reach)
+                .min_by_key(|(distance, _)| *distance)
+                .map(|(_, beam)| beam);
+            let bracket = brackets
+                .iter()
+                .filter(|bracket| bracket.x1 <= x && x <= bracket.x2)
+                .filter(|bracket| (bracket.y - y).abs() <= reach)
+                .min_by_key(|bracket| (bracket.y - y).abs());
+            let (x1, x2) = match (beam, bracket) {
+                (Some(beam), _) => (beam.x1 - staff.spacing * 2, beam.x2 + staff.spacing / 2),
+                (None, Some(bracket)) => (bracket.x1 - staff.spacing, bracket.x2),
+                _ => match Self::centred(&notes, x, actual, staff.spacing) {
+                    // Stems of up notes stand right of their heads
+                    Some((x1, x2))
+                        if x1 - staff.spacing <= x
+                            && x <= x2 + staff.spacing
+                            && stems.ends_on(
+                                x1,
+                                x2 + staff.spacing,
+                                y - reach,
+                                y + reach,
+                                Fixed::new(0),
+                            ) =>
+                    {
+                        (x1, x2)
+                    }
+                    _ => continue,
+                },
+            };
+            let members = notes
+                .iter()
+                .filter(|(_, obj)| x1 <= obj.point.x && obj.point.x <= x2)
+                .map(|(index, _)| *index)
+                .collect::<Vec<_>>();
+            if !members.is_empty() {
+                res.push((Tuplet::new(actual), members));
+            }
+        }
+        res
+    }
+
+    // Span of the run of note columns whose middle is closest to x
+    fn centred(
+        notes: &[&(usize, &Object)],
+        x: Fixed,
+        count: u8,
+        spacing: Fixed,
+    ) -> Option<(Fixed, Fixed)> {
+        let mut columns: Vec<Fixed> = vec![];
+        for (_, obj) in notes.iter() {
+            if columns
+                .last()
+                .is_none_or(|last| obj.point.x - *last >= spacing / 2)
+            {
+                columns.push(obj.point.x);
+            }
+        }
+        columns
+            .windows(usize::from(count))
+            .min_by_key(|run| ((run[0] + run[run.len() - 1]) / 2 - x).abs())
+            .map(|run| (run[0], run[run.len() - 1] + spacing / 2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Point, Quadrangle, VertLine};
+
+    fn staff() -> Staff {
+        Staff::new(
+            Fixed::new(0),
+            Fixed::new(500),
+            Fixed::new(100),
+            5,
+            Fixed::new(10),
+        )
+    }
+
+    fn object(t: Type, x: i64, y: i64) -> Object {
+        Object::new(t, Point::new(x, y))
+    }
+
+    #[test]
+    fn ratios() {
+        assert_eq!(
+            Tuplet::new(3),
+            Tuplet {
+                actual: 3,
+                normal: 2
+            }
+        );
+        assert_eq!(
+            Tuplet::new(5),
+            Tuplet {
+                actual: 5,
+                normal: 4
+            }
+        );
+        assert_eq!(
+            Tuplet::new(6),
+            Tuplet {
+                actual: 6,
+                normal: 4
+            }
+        );
+        assert_eq!(
+            Tuplet::new(2),
+            Tuplet {
+                actual: 2,
+                normal: 3
+            }
+        );
+        assert_eq!(Tuplet::new(3).scale(240), 160);
+    }
+
+    fn digit(actual: u8, x: i64, y: i64) -> Object {
+        let mut obj = object(Type::Digit(actual), x, y);
+        obj.small = true;
+        obj
+    }
+
+    fn beam() -> Beam {
+        Beam::new(&Quadrangle {
+            points: [
+                Point::new(26, 80),
+                Point::new(86, 80),
+                Point::new(86, 84),
+                Point::new(26, 84),
+            ],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn digit_over_a_beam() {
+        let objects = [
+            object(Type::Head(4), 20, 120),
+            object(Type::Head(4), 50, 125),
+            object(Type::Head(4), 80, 130),
+            digit(3, 50, 72),
+            object(Type::Head(4), 120, 130),
+        ];
+        let objects = objects.iter().enumerate().collect::<Vec<_>>();
+        let found = Tuplet::find(&objects, &staff(), &[beam()], &Stems::default(), &[]);
+        assert_eq!(found, vec![(Tuplet::new(3), vec![0, 1, 2])]);
+        // Full size digits are no tuplet numbers
+        let objects = [
+            object(Type::Head(4), 20, 120),
+            object(Type::Head(4), 50, 125),
+            object(Type::Head(4), 80, 130),
+            object(Type::Digit(3), 50, 72),
+        ];
+        let objects = objects.iter().enumerate().collect::<Vec<_>>();
+        assert!(Tuplet::find(&objects, &staff(), &[beam()], &Stems::default(), &[]).is_empty());
+    }
+
+    #[test]
+    fn digit_over_a_bracket() {
+        let objects = [
+            object(Type::Head(2), 20, 120),
+            object(Type::Head(2), 60, 125),
+            object(Type::Head(2), 100, 130),
+            digit(3, 64, 72),
+            object(Type::Head(2), 140, 130),
+        ];
+        let objects = objects.iter().enumerate().collect::<Vec<_>>();
+        let bracket = HorzLine::new(20, 110, 75, 1);
+        let found = Tuplet::find(&objects, &staff(), &[], &Stems::default(), &[bracket]);
+        assert_eq!(found, vec![(Tuplet::new(3), vec![0, 1, 2])]);
+    }
+
+    #[test]
+    fn digit_by_stem_ends() {
+        let objects = [
+            object(Type::Head(2), 20, 120),
+            object(Type::Head(2), 60, 125),
+            object(Type::Head(2), 100, 130),
+            digit(3, 60, 175),
+            object(Type::Head(2), 140, 130),
+        ];
+        let mut stems = Stems::default();
+        for (x, y) in [(20, 120), (60, 125), (100, 130), (140, 130)].iter() {
+            stems.push(0, &VertLine::new(*x, *y, *y + 35));
+        }
+        let objects = objects.iter().enumerate().collect::<Vec<_>>();
+        let found = Tuplet::find(&objects, &staff(), &[], &stems, &[]);
+        assert_eq!(found, vec![(Tuplet::new(3), vec![0, 1, 2])]);
+        // Nor too far from them
+        let objects = [
+            object(Type::Head(2), 20, 120),
+            object(Type::Head(2), 60, 125),
+            object(Type::Head(2), 100, 130),
+            digit(3, 60, 200),
+        ];
+        let objects = objects.iter().enumerate().collect::<Vec<_>>();
+        assert!(Tuplet::find(&objects, &staff(), &[], &stems, &[]).is_empty());
+        // Time signature digits stand inside the staff
+        let objects = [digit(3, 10, 110), object(Type::Head(4), 40, 120)];
+        let objects = objects.iter().enumerate().collect::<Vec<_>>();
+        assert!(Tuplet::find(&objects, &staff(), &[], &stems, &[]).is_empty());
+    }
+
+    #[test]
+    fn fingerings_and_volta_numbers_are_no_tuplets() {
+        // Plain eighths under the staff, a volta 2 high above and a fingering 3 right
+        // under a head
+        let objects = [
+            object(Type::Head(4), 20, 150),
+            object(Type::Head(4), 50, 155),
+            object(Type::Head(4), 80, 160),
+            digit(2, 22, 40),
+            digit(3, 51, 178),
+        ];
+        let mut stems = Stems::default();
+        for (x, y) in [(26, 150), (56, 155), (86, 160)].iter() {
+            stems.push(0, &VertLine::new(*x, 80, *y));
+        }
+        let objects = objects.iter().enumerate().collect::<Vec<_>>();
+        assert!(Tuplet::find(&objects, &staff(), &[beam()], &stems, &[]).is_empty());
+    }
+}
@@ -1,10 +1,16 @@
 use ghakuf::messages::{Message, MetaEvent, MidiEvent};
 use ghakuf::writer::*;
+use std::collections::{HashMap, HashSet};
 use std::path;
 
-use crate::recognizer::Collector;
+use crate::common::{Articulation, Dynamic};
+use crate::recognizer::{unfold, Change, Chord, Collector, Grace, Harmony, Pitch, Rest, Wedge};
 
 pub struct Smf {
+    // Tempo until the score marks one
+    bpm: u32,
+    // Play the chord symbols on a channel of their own
+    accompany: bool,
     messages: Vec<Message>,
 }
 
@@ -21,6 +27,19 @@ pub enum Action {
         note: u8,
         velocity: u8,
     },
+    KeySignature {
+        time: u32,
+        fifths: i8,
+    },
+    TimeSignature {
+        time: u32,
+        beats: u8,
+        beat_type: u8,
+    },
+    Lyric {
+        time: u32,
+        text: String,
+    },
 }
 
 impl Action {
@@ -28,27 +47,230 @@ impl Action {
         match self {
             Action::NoteOn { time, .. } => *time,
             Action::NoteOff { time, .. } => *time,
+            Action::KeySignature { time, .. } => *time,
+            Action::TimeSignature { time, .. } => *time,
+            Action::Lyric { time, .. } => *time,
         }
     }
 }
 
+// Dynamics and hairpins of a staff by the time they take effect
+#[derive(Default)]
+struct Loudness {
+    dynamics: Vec<(u32, Dynamic)>,
+    wedges: Vec<(u32, Wedge)>,
+}
+
+impl Loudness {
+    const DEFAULT: u8 = 96;
+
+    fn sort(&mut self) {
+        self.dynamics.sort_by_key(|(time, _)| *time);
+        self.wedges.sort_by_key(|(time, _)| *time);
+    }
+
+    // Level set by the last dynamic up to the time, unmarked accents leave it alone
+    fn level(&self, time: u32) -> u8 {
+        self.dynamics
+            .iter()
+            .take_while(|(at, _)| *at <= time)
+            .filter_map(|(_, dynamic)| dynamic.velocity())
+            .last()
+            .unwrap_or(Self::DEFAULT)
+    }
+
+    // Hairpins ramp from the level where they open to the next dynamic after they
+    // close, or a step up or down when none follows
+    fn velocity(&self, time: u32) -> u8 {
+        if let Some((_, dynamic)) = self.dynamics.iter().find(|(at, _)| *at == time) {
+            return dynamic.attack();
+        }
+        let opened = self
+            .wedges
+            .iter()
+            .rev()
+            .find(|(at, wedge)| *at <= time && *wedge != Wedge::Stop);
+        let (start, wedge) = match opened {
+            Some(&(start, wedge)) => (start, wedge),
+            None => return self.level(time),
+        };
+        let stop = self
+            .wedges
+            .iter()
+            .find(|(at, wedge)| *at > start && *wedge == Wedge::Stop)
+            .map(|(at, _)| *at);
+        let from = self.level(start);
+        let to = stop
+            .and_then(|stop| {
+                self.dynamics
+                    .iter()
+                    .find(|(at, _)| *at >= stop)
+                    .and_then(|(_, dynamic)| dynamic.velocity())
+            })
+            .unwrap_or(match wedge {
+                Wedge::Crescendo => from.saturating_add(32).min(127),
+                _ => from.saturating_sub(32).max(1),
+            });
+        let stop = match stop {
+            Some(stop) if time < stop => stop,
+            None => return from,
+            // Past the end of the hairpin until the next dynamic
+            _ if self.level(time) == from => return to,
+            _ => return self.level(time),
+        };
+        let progress = f64::from(time - start) / f64::from(stop - start);
+        (f64::from(from) + (f64::from(to) - f64::from(from)) * progress).round() as u8
+    }
+}
+
+// Time held still under fermatas, by where they start; the longest one starting there
+// doubles its note and every voice waits for it from its end, what sounds under it
+// keeps its time
+#[derive(Default)]
+struct Pauses {
+    pauses: HashMap<u32, u32>,
+}
+
+impl Pauses {
+    fn put(&mut self, time: u32, len: u32) {
+        let pause = self.pauses.entry(time).or_default();
+        *pause = (*pause).max(len);
+    }
+
+    fn stretch(&self, time: u32) -> u32 {
+        time + self
+            .pauses
+            .iter()
+            .filter(|(at, pause)| **at + **pause <= time)
+            .map(|(_, pause)| pause)
+            .sum::<u32>()
+    }
+}
+
 impl Smf {
     pub fn new(bpm: u32) -> Self {
-        let tempo: u32 = 60 * 1_000_000 / bpm;
         Self {
-            messages: vec![
-                Message::MetaEvent {
-                    delta_time: 0,
-                    event: MetaEvent::SetTempo,
-                    data: [(tempo >> 16) as u8, (tempo >> 8) as u8, tempo as u8].to_vec(),
-                },
-                Message::MetaEvent {
-                    delta_time: 0,
-                    event: MetaEvent::EndOfTrack,
-                    data: Vec::new(),
-                },
-                Message::TrackChange,
-            ],
+            bpm,
+            accompany: false,
+            messages: vec![],
+        }
+    }
+
+    pub fn accompany(mut self) -> Self {
+        self.accompany = true;
+        self
+    }
+
+    // Chord symbols held until the next one, across barlines, the last to the end; the
+    // bass two octaves and the chord one octave below the middle C
+    fn accompaniment(
+        harmonies: &mut [(u32, &Harmony)],
+        end: u32,
+        ch: u8,
+        pauses: &Pauses,
+        actions: &mut Vec<Action>,
+    ) {
+        harmonies.sort_by_key(|(start, _)| *start);
+        for (index, (start, harmony)) in harmonies.iter().enumerate() {
+            let end = harmonies.get(index + 1).map_or(end, |(next, _)| *next);
+            if end <= *start {
+                continue;
+            }
+            let root = Pitch::new(harmony.root.step - 7, harmony.root.alter).midi();
+            let bass = harmony.bass.unwrap_or(harmony.root);
+            let bass = Pitch::new(bass.step - 14, bass.alter).midi();
+            let tones = std::iter::once(bass).chain(
+                harmony
+                    .intervals()
+                    .into_iter()
+                    .map(|interval| root + interval),
+            );
+            for note in tones {
+                actions.push(Action::NoteOn {
+                    time: pauses.stretch(*start),
+                    ch,
+                    note: note as u8,
+                    velocity: 64,
+                });
+                actions.push(Action::NoteOff {
+                    time: pauses.stretch(end),
+                    ch,
+                    note: note as u8,
+                    velocity: 64,
+                });
+            }
+        }
+    }
+
+    // Tempo from each point on; ritardandos and accelerandos slow down or speed up by
+    // a quarter a beat at a time, until the next marking or over a measure
+    fn tempo_map(&self, changes: &[(u32, Change)]) -> Vec<(u32, u32)> {
+        let mut map = vec![(0, self.bpm)];
+        let (mut base, mut current) = (self.bpm, self.bpm);
+        for (index, &(time, change)) in changes.iter().enumerate() {
+            match change {
+                Change::Set(bpm) => {
+                    base = bpm;
+                    current = bpm;
+                    map.push((time, bpm));
+                }
+                Change::Resume => {
+                    current = base;
+                    map.push((time, base));
+                }
+                Change::Ritardando | Change::Accelerando => {
+                    let end = changes.get(index + 1).map_or(time + 1920, |next| next.0);
+                    let target = match change {
+                        Change::Ritardando => current * 3 / 4,
+                        _ => current * 5 / 4,
+                    };
+                    let steps = ((end - time) / 480).max(1);
+                    for step in 0..steps {
+                        let bpm = (i64::from(current)
+                            + (i64::from(target) - i64::from(current)) * i64::from(step + 1)
+                                / i64::from(steps)) as u32;
+                        map.push((time + step * 480, bpm));
+                    }
+                    current = target;
+                }
+            }
+        }
+        map
+    }
+
+    // Start and end of each grace note before a note of the given length; slashed ones are
+    // as short as possible, together they take no more than half the note
+    fn graces<'a>(graces: &[&'a Chord], len: u32, time: u32) -> Vec<(u32, &'a Chord, u32)> {
+        let wanted = graces
+            .iter()
+            .map(|grace| match grace.grace {
+                Some(Grace::Acciaccatura) => 60,
+                _ => 120,
+            })
+            .collect::<Vec<u32>>();
+        let total = wanted.iter().sum::<u32>();
+        let available = total.min(len / 2);
+        let mut at = time;
+        graces
+            .iter()
+            .zip(wanted)
+            .map(|(grace, wanted)| {
+                let end = at + wanted * available / total.max(1);
+                let res = (at, *grace, end);
+                at = end;
+                res
+            })
+            .collect()
+    }
+
+    // Staves take the channels in turn, leaving out the drums on channel 10 and starting
+    // over past the sixteenth
+    fn channel(staff: usize) -> u8 {
+        let ch = (staff % 15) as u8;
+        if ch >= 9 {
+            ch + 1
+        } else {
+            ch
         }
     }
 
@@ -56,32 +278,211 @@ impl Smf {
         use crate::recognizer::Note;
 
         let mut actions = vec![];
-        for (i, collector) in collectors.iter().enumerate() {
+        // Measures in playing order, repeats written out
+        let order = collectors
+            .first()
+            .map_or(vec![], |collector| unfold(&collector.measures));
+        // Voices of a staff share its dynamics
+        let mut loudness = collectors
+            .iter()
+            .map(|_| Loudness::default())
+            .collect::<Vec<_>>();
+        let mut pauses = Pauses::default();
+        let mut changes = vec![];
+        for collector in collectors.iter() {
             let mut time = 0;
-            for note in collector.notes.iter() {
-                match note {
-                    Note::Note(data, len) => {
-                        for tone in data.iter() {
-                            actions.push(Action::NoteOn {
-                                time,
-                                ch: i as u8,
-                                note: *tone as u8,
-                                velocity: 96,
-                            });
-                            actions.push(Action::NoteOff {
-                                time: time + *len as u32,
-                                ch: i as u8,
-                                note: *tone as u8,
-                                velocity: 64,
-                            });
+            for &number in order.iter() {
+                for note in collector.measure(number).iter() {
+                    if let Note::Rest(Rest { fermata: true, .. }) = note {
+                        pauses.put(time, note.len() as u32);
+                    }
+                    if let Note::Chord(chord) = note {
+                        let staff = &mut loudness[collector.staff];
+                        staff
+                            .dynamics
+                            .extend(chord.dynamic.map(|dynamic| (time, dynamic)));
+                        staff
+                            .wedges
+                            .extend(chord.wedges.iter().map(|wedge| (time, *wedge)));
+                        changes.extend(chord.tempo.as_ref().map(|tempo| (time, tempo.change)));
+                        if chord.articulations.contains(&Articulation::Fermata) {
+                            pauses.put(time, chord.len as u32);
                         }
-                        time += *len as u32;
                     }
-                    Note::Rest(len) => {
-                        time += *len as u32;
+                    time += note.len() as u32;
+                }
+            }
+        }
+        for staff in loudness.iter_mut() {
+            staff.sort();
+        }
+        changes.sort_by_key(|(time, _)| *time);
+        // Tempo changes go on a track of their own ahead of the notes
+        let mut clock = 0;
+        for (time, bpm) in self.tempo_map(&changes) {
+            let time = pauses.stretch(time);
+            let tempo: u32 = 60 * 1_000_000 / bpm.max(1);
+            self.messages.push(Message::MetaEvent {
+                delta_time: time - clock,
+                event: MetaEvent::SetTempo,
+                data: [(tempo >> 16) as u8, (tempo >> 8) as u8, tempo as u8].to_vec(),
+            });
+            clock = time;
+        }
+        self.messages.push(Message::MetaEvent {
+            delta_time: 0,
+            event: MetaEvent::EndOfTrack,
+            data: Vec::new(),
+        });
+        self.messages.push(Message::TrackChange);
+        // Chord symbols with their start, and where the music ends
+        let mut harmonies = vec![];
+        let mut end = 0;
+        for (i, collector) in collectors.iter().enumerate() {
+            let mut time = 0;
+            // Tones tied over from the previous chord keep sounding
+            let mut held: HashSet<Pitch> = HashSet::new();
+            // Grace notes waiting for the note they lead into
+            let mut graces: Vec<&Chord> = vec![];
+            // Times each measure has been played, repeats sing the next verse
+            let mut passes: HashMap<usize, u8> = HashMap::new();
+            for &number in order.iter() {
+                let measure = &collector.measures[number];
+                if collector.voice == 1 {
+                    for (onset, harmony) in measure.harmonies.iter() {
+                        harmonies.push((time + *onset as u32, harmony));
+                    }
+                }
+                let pass = passes.entry(number).or_default();
+                *pass += 1;
+                let verse = *pass;
+                // Signatures are global in MIDI, the first staff carries them
+                if i == 0 {
+                    if let Some(signature) = measure.time {
+                        actions.push(Action::TimeSignature {
+                            time: pauses.stretch(time),
+                            beats: signature.beats,
+                            beat_type: signature.beat_type,
+                        });
+                    }
+                    if let Some(key) = measure.key {
+                        actions.push(Action::KeySignature {
+                            time: pauses.stretch(time),
+                            fifths: key.fifths,
+                        });
+                    }
+                }
+                for note in collector.measure(number).iter() {
+                    if let Note::Chord(chord @ Chord { grace: Some(_), .. }) = note {
+                        graces.push(chord);
+                        continue;
+                    }
+                    // Ties that lead nowhere end here
+                    let tones = match note {
+                        Note::Chord(chord) if chord.cue => &[],
+                        Note::Chord(chord) => chord.tones.as_slice(),
+                        Note::Rest(_) | Note::Space(_) => &[],
+                    };
+                    for tone in held.iter().filter(|tone| !tones.contains(tone)) {
+                        actions.push(Action::NoteOff {
+                            time: pauses.stretch(time),
+                            ch: Self::channel(collector.staff),
+                            note: tone.midi() as u8,
+                            velocity: 64,
+                        });
+                    }
+                    match note {
+                        Note::Chord(chord) if chord.cue => {
+                            // Cue notes only show what another part plays
+                            graces.clear();
+                            held.clear();
+                            time += chord.len as u32;
+                        }
+                        Note::Chord(chord) => {
+                            let len = &chord.len;
+                            // Graces are played on the beat, taking the time from the note
+                            let mut onset = time;
+                            for (at, grace, end) in Self::graces(&graces, *len as u32, time) {
+                                for tone in grace.tones.iter() {
+                                    actions.push(Action::NoteOn {
+                                        time: pauses.stretch(at),
+                                        ch: Self::channel(collector.staff),
+                                        note: tone.midi() as u8,
+                                        velocity: loudness[collector.staff].velocity(at),
+                                    });
+                                    actions.push(Action::NoteOff {
+                                        time: pauses.stretch(end),
+                                        ch: Self::channel(collector.staff),
+                                        note: tone.midi() as u8,
+                                        velocity: 64,
+                                    });
+                                }
+                                onset = end;
+                            }
+                            graces.clear();
+                            let lyric = chord
+                                .lyrics
+                                .iter()
+                                .find(|lyric| lyric.verse == verse)
+                                .or_else(|| chord.lyrics.iter().find(|lyric| lyric.verse == 1));
+                            if let Some(lyric) = lyric {
+                                let end = if lyric.hyphen { "-" } else { " " };
+                                actions.push(Action::Lyric {
+                                    time: pauses.stretch(onset),
+                                    text: format!("{}{}", lyric.text, end),
+                                });
+                            }
+                            let sounding = chord
+                                .articulations
+                                .iter()
+                                .map(|articulation| articulation.shorten(*len))
+                                .min()
+                                .unwrap_or(*len);
+                            let stress = chord
+                                .articulations
+                                .iter()
+                                .map(|articulation| articulation.stress())
+                                .max()
+                                .unwrap_or(0);
+                            let velocity = loudness[collector.staff]
+                                .velocity(time)
+                                .saturating_add(stress)
+                                .min(127);
+                            for tone in chord.tones.iter() {
+                                if !held.contains(tone) {
+                                    actions.push(Action::NoteOn {
+                                        time: pauses.stretch(onset),
+                                        ch: Self::channel(collector.staff),
+                                        note: tone.midi() as u8,
+                                        velocity,
+                                    });
+                                }
+                                if !chord.tied.contains(tone) {
+                                    actions.push(Action::NoteOff {
+                                        time: pauses.stretch(time + sounding as u32),
+                                        ch: Self::channel(collector.staff),
+                                        note: tone.midi() as u8,
+                                        velocity: 64,
+                                    });
+                                }
+                            }
+                            held = chord.tied.iter().cloned().collect();
+                            time += *len as u32;
+                        }
+                        Note::Rest(_) | Note::Space(_) => {
+                            graces.clear();
+                            held.clear();
+                            time += note.len() as u32;
+                        }
                     }
                 }
             }
+            end = end.max(time);
+        }
+        if self.accompany {
+            // First channel after the staves
+            let ch = Self::channel(collectors.iter().map(|c| c.staff + 1).max().unwrap_or(0));
+            Self::accompaniment(&mut harmonies, end, ch, &pauses, &mut actions);
         }
         actions.sort_by(|a, b| Action::time(a).cmp(&Action::time(b)));
         let mut clock = 0;
@@ -121,6 +522,38 @@ impl Smf {
                         },
                     });
                 }
+                Action::TimeSignature {
+                    time,
+                    beats,
+                    beat_type,
+                } => {
+                    let delta = time - clock;
+                    clock = *time;
+                    self.messages.push(Message::MetaEvent {
+                        delta_time: delta,
+                        event: MetaEvent::TimeSignature,
+                        data: [*beats, beat_type.trailing_zeros() as u8, 24, 8].to_vec(),
+                    });
+                }
+                Action::Lyric { time, text } => {
+                    let delta = time - clock;
+                    clock = *time;
+                    // Lyrics were read as Latin-1
+                    self.messages.push(Message::MetaEvent {
+                        delta_time: delta,
+                        event: MetaEvent::Lyric,
+                        data: text.chars().map(|c| c as u8).collect(),
+                    });
+                }
+                Action::KeySignature { time, fifths } => {
+                    let delta = time - clock;
+                    clock = *time;
+                    self.messages.push(Message::MetaEvent {
+                        delta_time: delta,
+                        event: MetaEvent::KeySignature,
+                        data: [*fifths as u8, 0].to_vec(),
+                    });
+                }
             }
         }
 
@@ -133,8 +566,97 @@ impl Smf {
         let mut writer = Writer::new();
         writer.running_status(true);
         for message in &self.messages {
-            writer.push(&message);
+            writer.push(message);
         }
-        let _ = writer.write(&path);
+        let _ = writer.write(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dynamics_set_the_level() {
+        let mut loudness = Loudness::default();
+        loudness.dynamics.push((960, Dynamic::Sfz));
+        loudness.dynamics.push((480, Dynamic::P));
+        loudness.sort();
+        assert_eq!(loudness.velocity(0), Loudness::DEFAULT);
+        assert_eq!(loudness.velocity(480), 49);
+        assert_eq!(loudness.velocity(960), 127);
+        assert_eq!(loudness.velocity(1440), 49);
+    }
+
+    #[test]
+    fn hairpin_ramps_to_the_next_dynamic() {
+        let mut loudness = Loudness::default();
+        loudness.dynamics.push((0, Dynamic::P));
+        loudness.dynamics.push((960, Dynamic::F));
+        loudness.wedges.push((0, Wedge::Crescendo));
+        loudness.wedges.push((960, Wedge::Stop));
+        loudness.sort();
+        assert_eq!(loudness.velocity(0), 49);
+        assert_eq!(loudness.velocity(480), 73);
+        assert_eq!(loudness.velocity(960), 96);
+    }
+
+    #[test]
+    fn hairpin_without_a_dynamic_steps() {
+        let mut loudness = Loudness::default();
+        loudness.wedges.push((0, Wedge::Diminuendo));
+        loudness.wedges.push((960, Wedge::Stop));
+        assert_eq!(loudness.velocity(480), 80);
+        assert_eq!(loudness.velocity(1920), 64);
+    }
+
+    #[test]
+    fn channels_skip_the_drums() {
+        let channels = (0..17).map(Smf::channel).collect::<Vec<_>>();
+        assert_eq!(
+            channels,
+            vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15, 0, 1]
+        );
+    }
+
+    #[test]
+    fn fermatas_hold_everything_after() {
+        let mut pauses = Pauses::default();
+        pauses.put(480, 480);
+        pauses.put(480, 240);
+        assert_eq!(pauses.stretch(0), 0);
+        assert_eq!(pauses.stretch(480), 480);
+        assert_eq!(pauses.stretch(960), 1440);
+    }
+
+    #[test]
+    fn fermatas_stretch_from_their_end() {
+        // A half note under a fermata against quarters in the other voice
+        let mut pauses = Pauses::default();
+        pauses.put(0, 960);
+        let quarters = [0, 480, 960, 1440].iter().map(|time| pauses.stretch(*time));
+        assert_eq!(quarters.collect::<Vec<_>>(), vec![0, 480, 1920, 2400]);
+        // Both end on the fermata's end, held as long again
+        assert_eq!(pauses.stretch(960), 1920);
+    }
+
+    #[test]
+    fn chords_held_until_the_next_symbol() {
+        let (c, g) = (Harmony::parse("C").unwrap(), Harmony::parse("G7").unwrap());
+        // G7 comes in the bar after C, and sounds to the end
+        let mut harmonies = vec![(1920 + 960, &g), (0, &c)];
+        let mut actions = vec![];
+        Smf::accompaniment(&mut harmonies, 5760, 2, &Pauses::default(), &mut actions);
+        let offs = actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::NoteOff { time, .. } => Some(*time),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            offs,
+            vec![2880, 2880, 2880, 2880, 5760, 5760, 5760, 5760, 5760]
+        );
     }
 }